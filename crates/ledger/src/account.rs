@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::asset::{self, Id as AssetId};
+
 /// A unique identifier for an account.
 ///
 /// Using u16 limits the system to 65,535 accounts, which is sufficient for
@@ -18,6 +20,21 @@ pub enum Type {
     Disputed,
     /// Sub-account recording funds that have been permanently charged back.
     Chargeback,
+    /// Sub-account holding zero-value markers that freeze the account after a chargeback.
+    Lock,
+    /// Sub-account holding negative UTXOs for money the account owes, e.g. after disputing a
+    /// deposit that was already spent.
+    Debt,
+    /// Sub-account standing for the outside world (bank, payment rail) as the counterparty of
+    /// deposits and withdrawals. It holds the negation of the money that entered the account, so
+    /// it is normally negative.
+    External,
+    /// Sub-account recording what the account drew from its credit line. It goes negative as
+    /// credit is used, never below the account's credit limit, and deposits bring it back up.
+    Credit,
+    /// Sub-account holding funds reserved by authorisation holds until they are captured or
+    /// voided. Each UTXO belongs to a single hold.
+    Pending,
 }
 
 impl Type {
@@ -29,49 +46,110 @@ impl Type {
             Type::Main => 0,
             Type::Disputed => 1,
             Type::Chargeback => 2,
+            Type::Lock => 3,
+            Type::Debt => 4,
+            Type::External => 5,
+            Type::Credit => 6,
+            Type::Pending => 7,
         }
     }
 }
 
-/// A complete account identifier combining user ID and account type.
+/// A complete account identifier combining user ID, account type and asset.
 ///
 /// This composite key enables the UTXO model to track funds in different states
-/// (Main, Disputed, Chargeback) as separate "accounts" while presenting a unified
-/// view to external callers. Ordering is by ID first, then by Type, ensuring
+/// (Main, Disputed, Chargeback) and different assets as separate "accounts" while presenting a
+/// unified view to external callers. Ordering is by ID first, then by Type and asset, ensuring
 /// all sub-accounts for a user are grouped together.
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-pub struct FullAccount((Id, Type));
+#[serde(from = "Repr", into = "Repr")]
+pub struct FullAccount {
+    id: Id,
+    typ: Type,
+    asset: AssetId,
+}
+
+/// Serialized form of a `FullAccount`, the asset is left out when it is the default one so
+/// data written before assets existed still reads back.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    WithAsset((Id, Type, AssetId)),
+    Default((Id, Type)),
+}
+
+impl From<Repr> for FullAccount {
+    fn from(value: Repr) -> Self {
+        match value {
+            Repr::WithAsset(value) => value.into(),
+            Repr::Default(value) => value.into(),
+        }
+    }
+}
+
+impl From<FullAccount> for Repr {
+    fn from(value: FullAccount) -> Self {
+        if value.asset == asset::DEFAULT {
+            Repr::Default((value.id, value.typ))
+        } else {
+            Repr::WithAsset((value.id, value.typ, value.asset))
+        }
+    }
+}
 
 impl From<Id> for FullAccount {
     fn from(value: Id) -> Self {
-        FullAccount((value, Type::Main))
+        (value, Type::Main).into()
     }
 }
 
 impl From<(Id, Type)> for FullAccount {
     fn from(value: (Id, Type)) -> Self {
-        FullAccount(value)
+        (value.0, value.1, asset::DEFAULT).into()
+    }
+}
+
+impl From<(Id, Type, AssetId)> for FullAccount {
+    fn from(value: (Id, Type, AssetId)) -> Self {
+        FullAccount {
+            id: value.0,
+            typ: value.1,
+            asset: value.2,
+        }
     }
 }
 
 impl FullAccount {
     /// Returns the numeric account identifier.
     pub fn id(&self) -> Id {
-        self.0.0
+        self.id
     }
 
-    /// Returns the sub-account type (Main, Disputed, or Chargeback).
+    /// Returns the sub-account type (Main, Disputed, Chargeback, Lock, Debt, External, Credit,
+    /// or Pending).
     pub fn typ(&self) -> Type {
-        self.0.1
+        self.typ
+    }
+
+    /// Returns the asset held in this sub-account.
+    pub fn asset(&self) -> AssetId {
+        self.asset
     }
 
     /// Serializes to bytes for hashing and storage keys.
     ///
-    /// Format: 2 bytes (ID, little-endian) + 1 byte (Type)
-    pub fn to_bytes(&self) -> [u8; 3] {
-        let mut bytes = [0u8; 3];
-        bytes[..2].copy_from_slice(&self.0.0.to_le_bytes());
-        bytes[2] = self.0.1.to_byte();
+    /// Format: 2 bytes (ID, little-endian) + 1 byte (Type) [+ 2 bytes (asset, little-endian)].
+    /// The asset is left out when it is the default one, so existing transaction IDs do not
+    /// change, otherwise the high bit of the Type byte flags it is present.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(5);
+        bytes.extend_from_slice(&self.id.to_le_bytes());
+        if self.asset == asset::DEFAULT {
+            bytes.push(self.typ.to_byte());
+        } else {
+            bytes.push(self.typ.to_byte() | 0x80);
+            bytes.extend_from_slice(&self.asset.to_le_bytes());
+        }
         bytes
     }
 }
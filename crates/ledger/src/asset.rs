@@ -0,0 +1,9 @@
+/// Identifies an asset (a currency, a token) held in the ledger.
+///
+/// Assets are opaque to the ledger: mapping them to codes like USD or BTC, and choosing the
+/// precision of their amounts, is up to the caller. Amounts of different assets are never
+/// added together.
+pub type Id = u16;
+
+/// The asset used when none is given, e.g. by ledgers holding a single currency.
+pub const DEFAULT: Id = 0;
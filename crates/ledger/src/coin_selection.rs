@@ -0,0 +1,201 @@
+use crate::{storage::UtxoOrder, transaction::Utxo};
+
+/// How many branches the exact-match search explores before giving up.
+const EXACT_MATCH_MAX_TRIES: usize = 100_000;
+
+/// Strategy used to pick the UTXOs spent by an operation.
+///
+/// Every UTXO selected beyond the requested amount comes back as a change output, so the
+/// strategy decides how fragmented balances become over time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// Spend the oldest UTXOs first until the amount is covered.
+    #[default]
+    OldestFirst,
+    /// Spend the largest UTXOs first, which keeps the number of inputs low.
+    LargestFirst,
+    /// Spend the smallest single UTXO that covers the amount, falling back to largest-first
+    /// when no single UTXO is enough.
+    SmallestSufficient,
+    /// Search for a set of UTXOs adding up to exactly the amount, so no change is created,
+    /// falling back to largest-first when there is none.
+    ExactMatch,
+}
+
+impl CoinSelection {
+    /// Returns the order in which the storage layer should list the candidates.
+    pub(crate) fn order(&self) -> UtxoOrder {
+        match self {
+            CoinSelection::OldestFirst => UtxoOrder::OldestFirst,
+            CoinSelection::LargestFirst | CoinSelection::ExactMatch => UtxoOrder::LargestFirst,
+            CoinSelection::SmallestSufficient => UtxoOrder::SmallestFirst,
+        }
+    }
+
+    /// Whether the storage layer can stop listing candidates once the target is covered.
+    ///
+    /// The other strategies need to see every candidate to find the best fit.
+    pub(crate) fn is_greedy(&self) -> bool {
+        matches!(
+            self,
+            CoinSelection::OldestFirst | CoinSelection::LargestFirst
+        )
+    }
+
+    /// Selects the UTXOs to spend out of `candidates`, listed in the strategy's order.
+    ///
+    /// When the candidates cannot cover `target` all of them are returned, and it is up to the
+    /// caller to notice the shortfall.
+    pub(crate) fn select(&self, candidates: Vec<Utxo>, target: i128) -> Vec<Utxo> {
+        match self {
+            CoinSelection::OldestFirst | CoinSelection::LargestFirst => {
+                accumulate(candidates, target)
+            }
+            CoinSelection::SmallestSufficient => {
+                if let Some(single) = candidates
+                    .iter()
+                    .find(|utxo| *utxo.amount() >= target)
+                    .copied()
+                {
+                    vec![single]
+                } else {
+                    accumulate(candidates.into_iter().rev().collect(), target)
+                }
+            }
+            CoinSelection::ExactMatch => match exact_match(&candidates, target) {
+                Some(selected) => selected,
+                None => accumulate(candidates, target),
+            },
+        }
+    }
+}
+
+/// Takes candidates in order until their sum covers the target.
+fn accumulate(candidates: Vec<Utxo>, target: i128) -> Vec<Utxo> {
+    let mut total = 0i128;
+    let mut selected = Vec::new();
+
+    for utxo in candidates {
+        if total >= target {
+            break;
+        }
+        total = total.saturating_add(*utxo.amount());
+        selected.push(utxo);
+    }
+
+    selected
+}
+
+/// Depth-first search for a subset of candidates adding up to exactly `target`.
+///
+/// Candidates are expected largest first, so the first branches tried use few inputs. Branches
+/// that cannot reach the target with the remaining candidates are pruned.
+fn exact_match(candidates: &[Utxo], target: i128) -> Option<Vec<Utxo>> {
+    if target <= 0 || candidates.iter().any(|utxo| *utxo.amount() <= 0) {
+        return None;
+    }
+
+    // remaining[i] is the sum of every candidate from position i on
+    let mut remaining = vec![0i128; candidates.len() + 1];
+    for (i, utxo) in candidates.iter().enumerate().rev() {
+        remaining[i] = remaining[i + 1].saturating_add(*utxo.amount());
+    }
+
+    // Positions of the candidates included so far, the search backtracks by popping them
+    let mut chosen: Vec<usize> = Vec::new();
+    let mut pos = 0;
+    let mut missing = target;
+    let mut tries = EXACT_MATCH_MAX_TRIES;
+
+    loop {
+        if missing == 0 {
+            return Some(chosen.into_iter().map(|i| candidates[i]).collect());
+        }
+
+        if pos < candidates.len() && remaining[pos] >= missing && tries > 0 {
+            tries -= 1;
+            // Including the candidate is tried before skipping it
+            let amount = *candidates[pos].amount();
+            if amount <= missing {
+                chosen.push(pos);
+                missing -= amount;
+            }
+            pos += 1;
+            continue;
+        }
+
+        // Dead end, undo the last inclusion and carry on without that candidate
+        let last = chosen.pop()?;
+        missing += *candidates[last].amount();
+        pos = last + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxos(amounts: &[i128]) -> Vec<Utxo> {
+        amounts
+            .iter()
+            .enumerate()
+            .map(|(pos, amount)| {
+                Utxo::new(
+                    ([0u8; 32], pos as u8).into(),
+                    (*amount).into(),
+                    crate::DEFAULT_ASSET,
+                )
+            })
+            .collect()
+    }
+
+    fn amounts(utxos: &[Utxo]) -> Vec<i128> {
+        utxos.iter().map(|utxo| *utxo.amount()).collect()
+    }
+
+    #[test]
+    fn greedy_strategies_stop_once_covered() {
+        let selected = CoinSelection::OldestFirst.select(utxos(&[10, 20, 30]), 25);
+        assert_eq!(amounts(&selected), vec![10, 20]);
+
+        let selected = CoinSelection::LargestFirst.select(utxos(&[30, 20, 10]), 25);
+        assert_eq!(amounts(&selected), vec![30]);
+
+        // Not enough, everything is returned
+        let selected = CoinSelection::OldestFirst.select(utxos(&[10, 20]), 50);
+        assert_eq!(amounts(&selected), vec![10, 20]);
+    }
+
+    #[test]
+    fn smallest_sufficient_prefers_a_single_utxo() {
+        let selected = CoinSelection::SmallestSufficient.select(utxos(&[5, 20, 30, 50]), 25);
+        assert_eq!(amounts(&selected), vec![30]);
+
+        // No single UTXO is enough, the largest ones are combined
+        let selected = CoinSelection::SmallestSufficient.select(utxos(&[5, 20, 30]), 45);
+        assert_eq!(amounts(&selected), vec![30, 20]);
+    }
+
+    #[test]
+    fn exact_match_avoids_change() {
+        let selected = CoinSelection::ExactMatch.select(utxos(&[50, 30, 20, 7]), 57);
+        assert_eq!(amounts(&selected), vec![50, 7]);
+
+        let selected = CoinSelection::ExactMatch.select(utxos(&[50, 30, 20]), 50);
+        assert_eq!(amounts(&selected), vec![50]);
+
+        // Without an exact match the largest UTXOs are used
+        let selected = CoinSelection::ExactMatch.select(utxos(&[50, 30, 20]), 45);
+        assert_eq!(amounts(&selected), vec![50]);
+    }
+
+    #[test]
+    fn exact_match_handles_large_candidate_sets() {
+        let selected = CoinSelection::ExactMatch.select(utxos(&vec![1; 100_000]), 50_000);
+        assert_eq!(selected.len(), 50_000);
+
+        // Even amounts never add up to an odd target, the search gives up and falls back
+        let selected = CoinSelection::ExactMatch.select(utxos(&vec![2; 100_000]), 99_999);
+        assert_eq!(amounts(&selected).iter().sum::<i128>(), 100_000);
+    }
+}
@@ -14,11 +14,22 @@
 //! - `Main`: Normal available balance
 //! - `Disputed`: Funds under dispute, frozen from spending
 //! - `Chargeback`: Funds that have been charged back
+//! - `Lock`: Zero-value markers that freeze an account after a chargeback
+//! - `Debt`: Negative UTXOs recording money owed after disputing already spent funds
+//! - `External`: The outside world, counterparty of every deposit and withdrawal
+//! - `Credit`: Credit drawn from the account's credit line
+//! - `Pending`: Funds reserved by authorisation holds
+//!
+//! Every sub-account also belongs to an asset (a currency, a token) and UTXOs of different
+//! assets never mix. A ledger operates on one asset, see [`Ledger::for_asset`].
+//!
+//! Every transaction balances, so the sum of all UTXOs of each asset across all accounts is
+//! always zero. See [`Ledger::trial_balance`].
 //!
 //! # Example
 //!
 //! ```rust,no_run
-//! use ledger::{Ledger, Amount};
+//! use ledger::{Ledger, Amount, DEFAULT_ASSET};
 //!
 //! async fn example() {
 //!     let ledger = Ledger::default();
@@ -27,8 +38,8 @@
 //!     let tx_id = ledger.deposit(1, "deposit-001".to_string(), Amount::from(1000)).await.unwrap();
 //!
 //!     // Check balance
-//!     let balance = ledger.get_balances(1).await.unwrap();
-//!     assert_eq!(*balance.available, 1000);
+//!     let balances = ledger.get_balances(1).await.unwrap();
+//!     assert_eq!(*balances[&DEFAULT_ASSET].available, 1000);
 //!
 //!     // Withdraw funds
 //!     ledger.withdraw(1, "withdraw-001".to_string(), Amount::from(500)).await.unwrap();
@@ -39,30 +50,40 @@
 
 mod account;
 mod amount;
+mod asset;
+mod coin_selection;
+mod rate;
+mod reference;
+mod retry;
 mod storage;
 mod transaction;
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
 };
 
-use futures::Stream;
+use futures::{Stream, TryStreamExt, stream};
 use serde::{Deserialize, Serialize};
 use storage::{Memory, Storage};
-use transaction::{HashId, Transaction, Utxo};
 
 pub use self::{
     account::{FullAccount, Id as AccountId, Type as AccountType},
     amount::Amount,
+    asset::{DEFAULT as DEFAULT_ASSET, Id as AssetId},
+    coin_selection::CoinSelection,
+    rate::Rate,
+    reference::{Kind as ReferenceKind, Reference},
+    retry::RetryPolicy,
+    storage::{BoxError, Cursor, Error as StorageError, HistoryQuery, Order, UtxoOrder},
+    transaction::{HashId, Transaction, Utxo, UtxoId},
 };
 
-/// A unique identifier for a transaction within an account's context.
-///
-/// References allow external systems to idempotently track transactions and enable
-/// lookups for dispute resolution. Each reference must be unique per account.
-pub type Reference = String;
+#[cfg(feature = "sqlite")]
+pub use self::storage::{OpenError, Sqlite};
 
 /// Errors that can occur during ledger operations.
 ///
@@ -78,7 +99,7 @@ pub enum Error {
     #[error("Not found")]
     NotFound,
 
-    /// Operation attempted on wrong transaction type (e.g., disputing a withdrawal).
+    /// Operation attempted on wrong transaction type (e.g., disputing a transfer).
     #[error("Wrong transaction type")]
     WrongType,
 
@@ -90,10 +111,47 @@ pub enum Error {
     #[error("Not enough in account")]
     NotEnough,
 
+    /// The account is frozen after a chargeback and must be unlocked first.
+    #[error("Account is locked")]
+    Locked,
+
+    /// The requested amount is zero or negative where a positive amount is required.
+    #[error("Invalid amount")]
+    InvalidAmount,
+
+    /// The sub-account is internal to the ledger and cannot be used by the operation.
+    #[error("Sub-account {0:?} cannot be used here")]
+    InvalidAccount(FullAccount),
+
     /// Arithmetic overflow or underflow during calculation.
     #[error("Overflow or underflow error")]
     Math,
 
+    /// Concurrent operations kept spending the selected UTXOs until the retry budget ran out.
+    #[error("Too much contention, gave up after retrying")]
+    Contention,
+
+    /// The dispute was already resolved or charged back.
+    #[error("Dispute already closed")]
+    DisputeClosed,
+
+    /// The authorisation hold expired and can no longer be captured.
+    #[error("Authorisation hold expired")]
+    HoldExpired,
+
+    /// The authorisation hold was already captured or voided.
+    #[error("Authorisation hold already closed")]
+    HoldClosed,
+
+    /// Converting the amount at the given rate does not give a whole amount, rounding it would
+    /// create or destroy value.
+    #[error("Inexact currency conversion")]
+    InexactConversion,
+
+    /// The sum of all UTXOs of an asset across all accounts is not zero, by the given amount.
+    #[error("Ledger does not balance for asset {0}, off by {1:?}")]
+    Unbalanced(AssetId, Amount),
+
     /// Internal invariant violation that should never occur.
     #[error("Invalid internal state")]
     Internal,
@@ -109,13 +167,14 @@ where
     S: Storage,
 {
     storage: Arc<S>, // TODO: implement
+    retry: RetryPolicy,
+    coin_selection: CoinSelection,
+    asset: AssetId,
 }
 
 impl Default for Ledger<Memory> {
     fn default() -> Self {
-        Ledger {
-            storage: Arc::new(Memory::default()),
-        }
+        Ledger::new(Memory::default())
     }
 }
 
@@ -125,14 +184,63 @@ impl Default for Ledger<Memory> {
 /// reconciliation straightforward: each category is simply the sum of its UTXOs.
 #[derive(Serialize, Deserialize, Clone, Debug, Copy)]
 pub struct Balances {
-    /// Funds available for withdrawal or transfer.
+    /// Funds available for withdrawal or transfer, net of any outstanding debt.
+    ///
+    /// Negative when the account owes more than it holds. Credit drawn is not included, see
+    /// `credit_used`.
     pub available: Amount,
     /// Funds currently under dispute, frozen from spending.
     pub disputed: Amount,
     /// Funds that have been charged back and are no longer accessible.
     pub chargeback: Amount,
-    /// Sum of available and disputed funds (excludes chargebacks).
+    /// Funds reserved by authorisation holds, waiting to be captured or voided.
+    pub pending: Amount,
+    /// Sum of available, disputed and pending funds (excludes chargebacks).
     pub total: Amount,
+    /// Credit drawn from the account's credit line and not repaid yet.
+    pub credit_used: Amount,
+    /// Credit that can still be drawn, zero if the limit was lowered below what is in use.
+    pub credit_remaining: Amount,
+    /// Whether the account is frozen after a chargeback.
+    pub locked: bool,
+}
+
+/// The balance of every sub-account in the ledger, see [`Ledger::trial_balance`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrialBalance {
+    /// Balance of every sub-account, sorted by account.
+    pub accounts: Vec<(FullAccount, Amount)>,
+    /// Sum of the balances of each asset, always zero.
+    pub totals: BTreeMap<AssetId, Amount>,
+}
+
+/// A sub-account whose running balance does not match its UTXOs, see
+/// [`Ledger::verify_balances`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BalanceDrift {
+    /// The sub-account.
+    pub account: FullAccount,
+    /// Balance kept by the storage layer.
+    pub cached: Amount,
+    /// Sum of the sub-account's unspent UTXOs.
+    pub actual: Amount,
+}
+
+/// One page of an account's history, see [`Ledger::get_history`].
+#[derive(Debug, Clone)]
+pub struct Page {
+    /// Transactions in the requested order.
+    pub transactions: Vec<Transaction>,
+    /// Cursor to request the next page with, `None` when there are no more transactions.
+    pub next: Option<Cursor>,
+}
+
+/// A client transaction that can be disputed, with the amount at stake.
+enum Disputable {
+    /// Funds that entered the account.
+    Deposit(Amount),
+    /// Funds that left the account.
+    Withdrawal(Amount),
 }
 
 /// A stream that yields unique account IDs, filtering out sub-accounts.
@@ -169,6 +277,53 @@ impl Stream for AccountIterator {
     }
 }
 
+/// Withdrawals spend client funds into the External sub-account and nowhere else.
+///
+/// Withdrawals stored before the External sub-account existed have no outputs at all.
+fn is_withdrawal(tx: &Transaction) -> bool {
+    !tx.inputs().is_empty()
+        && tx
+            .outputs()
+            .iter()
+            .all(|(output, _)| output.typ() == AccountType::External)
+}
+
+/// Sub-accounts making up a balance breakdown, in the order [`breakdown`] expects them.
+const BALANCE_SUB_ACCOUNTS: [AccountType; 6] = [
+    AccountType::Main,
+    AccountType::Debt,
+    AccountType::Disputed,
+    AccountType::Chargeback,
+    AccountType::Credit,
+    AccountType::Pending,
+];
+
+/// Builds the balance breakdown of one asset out of the balances of its sub-accounts.
+fn breakdown(figures: [i128; 6], limit: Amount, locked: bool) -> Result<Balances, Error> {
+    let [main, debt, disputed, chargeback, credit, pending] = figures;
+    let available = main.checked_add(debt).ok_or(Error::Math)?;
+    let credit_used = credit.checked_neg().ok_or(Error::Math)?;
+    let total = available
+        .checked_add(disputed)
+        .and_then(|total| total.checked_add(pending))
+        .ok_or(Error::Math)?;
+
+    Ok(Balances {
+        available: available.into(),
+        disputed: disputed.into(),
+        chargeback: chargeback.into(),
+        pending: pending.into(),
+        total: total.into(),
+        credit_used: credit_used.into(),
+        credit_remaining: limit
+            .checked_sub(credit_used)
+            .ok_or(Error::Math)?
+            .max(0)
+            .into(),
+        locked,
+    })
+}
+
 impl<S> Ledger<S>
 where
     S: Storage,
@@ -178,16 +333,108 @@ where
     /// This allows using custom storage implementations (e.g., database-backed)
     /// instead of the default in-memory storage.
     pub fn new(storage: S) -> Self {
+        Self::with_retry_policy(storage, RetryPolicy::default())
+    }
+
+    /// Creates a new ledger that retries conflicting operations as the policy says.
+    ///
+    /// See [`RetryPolicy`] for which operations are retried and when.
+    pub fn with_retry_policy(storage: S, retry: RetryPolicy) -> Self {
         Ledger {
             storage: Arc::new(storage),
+            retry,
+            coin_selection: CoinSelection::default(),
+            asset: DEFAULT_ASSET,
+        }
+    }
+
+    /// Returns a ledger operating on another asset over the same storage.
+    ///
+    /// Every operation of the returned ledger moves funds of that asset only, balances and
+    /// references of each asset are kept apart. Lock markers are shared, a chargeback in any
+    /// asset freezes the whole account.
+    pub fn for_asset(&self, asset: AssetId) -> Self {
+        Ledger {
+            storage: self.storage.clone(),
+            retry: self.retry,
+            coin_selection: self.coin_selection,
+            asset,
+        }
+    }
+
+    /// Returns the asset this ledger operates on.
+    pub fn asset(&self) -> AssetId {
+        self.asset
+    }
+
+    /// Returns a sub-account of `account` in the ledger's asset.
+    fn full_account(&self, account: AccountId, typ: AccountType) -> FullAccount {
+        (account, typ, self.asset).into()
+    }
+
+    /// Changes how the UTXOs spent by every operation are picked.
+    ///
+    /// Defaults to [`CoinSelection::OldestFirst`].
+    pub fn set_coin_selection(&mut self, coin_selection: CoinSelection) {
+        self.coin_selection = coin_selection;
+    }
+
+    /// Picks the unspent UTXOs of a sub-account to cover `amount`, following the configured
+    /// coin selection strategy.
+    ///
+    /// When the sub-account cannot cover the amount all of its UTXOs are returned, so callers
+    /// compare the selected total with the amount.
+    async fn select_inputs(
+        &self,
+        account: &FullAccount,
+        amount: Amount,
+    ) -> Result<Vec<Utxo>, Error> {
+        let strategy = self.coin_selection;
+        let candidates = self
+            .storage
+            .get_unspent_sorted(
+                account,
+                strategy.order(),
+                strategy.is_greedy().then_some(amount),
+            )
+            .await?;
+        Ok(strategy.select(candidates, *amount))
+    }
+
+    /// Runs an operation, retrying it while a concurrent operation spends its inputs first, or
+    /// repays the debt or credit it was about to repay.
+    ///
+    /// Every attempt selects its inputs and reads the balances again. Failed attempts leave no
+    /// trace in storage, so retrying is always safe. Any other outcome is returned as is.
+    async fn with_retry<T, F, Fut>(&self, mut operation: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(Error::Storage(storage::Error::SpentUtxo(_) | storage::Error::Overpaid(_))) => {
+                    if attempt >= self.retry.max_attempts {
+                        return Err(Error::Contention);
+                    }
+                    tokio::time::sleep(self.retry.backoff(attempt - 1)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
         }
     }
 
     /// Deposits funds into an account, creating new UTXOs.
     ///
-    /// Deposits are transactions with no inputs and one output, effectively creating
-    /// new money in the system. The reference must be unique per account to ensure
-    /// idempotency and enable dispute lookups.
+    /// Deposits are transactions with no inputs. The money does not come from nothing: the
+    /// account's External sub-account is debited by the same amount, so the transaction still
+    /// sums to zero. The reference must be unique per account to ensure idempotency and enable
+    /// dispute lookups.
+    ///
+    /// If the account owes money (see [`Ledger::dispute`]), the deposit pays the debt down
+    /// first and only the remainder is credited to the Main sub-account.
     ///
     /// # Arguments
     /// * `account` - The account to credit
@@ -196,13 +443,38 @@ where
     ///
     /// # Returns
     /// The transaction hash ID on success
+    ///
+    /// # Errors
+    /// - `Error::InvalidAmount` if the amount is zero or negative
+    /// - `Error::Contention` if concurrent operations kept repaying the same debt
     pub async fn deposit(
         &self,
         account: AccountId,
-        reference: Reference,
+        reference: impl Into<Reference>,
         amount: Amount,
     ) -> Result<HashId, Error> {
-        let new_tx = Transaction::new(vec![], vec![(account.into(), amount)], reference, None)?;
+        if *amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let reference = reference.into();
+        self.with_retry(|| self.try_deposit(account, &reference, amount))
+            .await
+    }
+
+    /// A single attempt of [`Ledger::deposit`].
+    async fn try_deposit(
+        &self,
+        account: AccountId,
+        reference: &Reference,
+        amount: Amount,
+    ) -> Result<HashId, Error> {
+        let mut outputs = self.credit_outputs(account, amount).await?;
+        outputs.push((
+            self.full_account(account, AccountType::External),
+            amount.checked_neg().ok_or(Error::Math)?.into(),
+        ));
+        let new_tx = Transaction::new(vec![], outputs, reference.clone(), None)?;
         let tx_id = new_tx.id();
         self.storage.store_tx(new_tx).await?;
         Ok(tx_id)
@@ -219,48 +491,342 @@ where
         }
     }
 
-    /// Retrieves the balance breakdown for an account.
+    /// Returns one page of the transactions an account took part in.
     ///
-    /// The UTXO model makes balance calculation straightforward: simply sum all
-    /// unspent outputs for each sub-account type. This naturally provides an
-    /// audit trail and prevents double-counting.
-    pub async fn get_balances(&self, account: AccountId) -> Result<Balances, Error> {
-        let main = self
-            .storage
-            .get_unspent(&(account, AccountType::Main).into(), None)
-            .await?
-            .into_iter()
-            .map(|u| *u.amount())
-            .sum::<i128>();
-        let disputed = self
+    /// Deposits, withdrawals, disputes and internal movements are all included, whether the
+    /// account spent or received funds. Passing a sub-account narrows the history to it,
+    /// otherwise every sub-account is included. Pass the returned `next` cursor as
+    /// `query.after` to fetch the following page.
+    pub async fn get_history(
+        &self,
+        account: AccountId,
+        sub_account: Option<AccountType>,
+        query: HistoryQuery,
+    ) -> Result<Page, Error> {
+        let transactions = self
             .storage
-            .get_unspent(&(account, AccountType::Disputed).into(), None)
-            .await?
+            .get_history(account, sub_account, &query)
+            .await?;
+
+        // A short page means the history is exhausted
+        let next = if query.limit > 0 && transactions.len() == query.limit {
+            transactions.last().map(Cursor::from)
+        } else {
+            None
+        };
+
+        Ok(Page { transactions, next })
+    }
+
+    /// Streams every transaction an account took part in, see [`Ledger::get_history`].
+    ///
+    /// The history is fetched from storage one page of `query.limit` transactions at a time,
+    /// starting after `query.after` if set.
+    pub fn history_stream(
+        &self,
+        account: AccountId,
+        sub_account: Option<AccountType>,
+        query: HistoryQuery,
+    ) -> impl Stream<Item = Result<Transaction, Error>> + '_ {
+        stream::try_unfold(Some(query), move |query| async move {
+            let Some(query) = query else {
+                return Ok(None);
+            };
+
+            let page = self.get_history(account, sub_account, query).await?;
+            let next = page.next.map(|cursor| HistoryQuery {
+                after: Some(cursor),
+                ..query
+            });
+
+            Ok::<_, Error>(Some((
+                stream::iter(page.transactions.into_iter().map(Ok)),
+                next,
+            )))
+        })
+        .try_flatten()
+    }
+
+    /// Retrieves the balance breakdown for an account, per asset.
+    ///
+    /// The balance of each sub-account type is the sum of its unspent outputs. The storage
+    /// layer keeps that sum up to date as transactions are stored, so reads do not walk the
+    /// UTXOs; see [`Ledger::verify_balances`] to check the running balances against them.
+    ///
+    /// Outstanding debt is netted against the Main sub-account, so `available` is negative
+    /// while the account owes more than it holds.
+    ///
+    /// Every asset the account ever held is listed, plus the ledger's own asset, so an account
+    /// without transactions reports zero balances for it. The lock status is the same for all
+    /// of them.
+    pub async fn get_balances(
+        &self,
+        account: AccountId,
+    ) -> Result<BTreeMap<AssetId, Balances>, Error> {
+        self.balances(account, None).await
+    }
+
+    /// Retrieves the balance breakdown for an account as it was at `timestamp`.
+    ///
+    /// Every transaction stored with a timestamp at or before the given instant (microseconds
+    /// since the Unix epoch) is taken into account, later ones are ignored. The breakdown is
+    /// computed the same way as [`Ledger::get_balances`], including the lock status.
+    pub async fn get_balances_at(
+        &self,
+        account: AccountId,
+        timestamp: u64,
+    ) -> Result<BTreeMap<AssetId, Balances>, Error> {
+        self.balances(account, Some(timestamp)).await
+    }
+
+    /// Computes the balance of every sub-account in the ledger, and checks the balances of each
+    /// asset sum to zero.
+    ///
+    /// Every transaction balances, deposits and withdrawals included thanks to the External
+    /// sub-accounts, so the money held by clients always equals the money that entered minus
+    /// the money that left. Any other result means the books are corrupted.
+    ///
+    /// # Errors
+    /// - `Error::Unbalanced` with the asset and the difference if its balances do not sum to
+    ///   zero
+    pub async fn trial_balance(&self) -> Result<TrialBalance, Error> {
+        let mut accounts_stream = self.storage.get_accounts().await;
+        let mut accounts = Vec::new();
+        let mut totals = BTreeMap::<AssetId, i128>::new();
+
+        while let Some(account) = accounts_stream.try_next().await? {
+            let balance = self.balance_of(&account).await?;
+            let total = totals.entry(account.asset()).or_default();
+            *total = total.checked_add(balance).ok_or(Error::Math)?;
+            accounts.push((account, balance.into()));
+        }
+
+        if let Some((asset, total)) = totals.iter().find(|(_, total)| **total != 0) {
+            return Err(Error::Unbalanced(*asset, (*total).into()));
+        }
+
+        Ok(TrialBalance {
+            accounts,
+            totals: totals
+                .into_iter()
+                .map(|(asset, total)| (asset, total.into()))
+                .collect(),
+        })
+    }
+
+    /// Recomputes the balance of every sub-account from its UTXOs and compares it with the
+    /// running balance kept by the storage layer.
+    ///
+    /// Reads use the running balances, so this is the way to check they are still trustworthy.
+    /// Returns every sub-account that drifted, an empty list means the cache is sound.
+    pub async fn verify_balances(&self) -> Result<Vec<BalanceDrift>, Error> {
+        let mut accounts = self.storage.get_accounts().await;
+        let mut drifts = Vec::new();
+
+        while let Some(account) = accounts.try_next().await? {
+            let cached = self.storage.get_balance(&account).await?;
+            let actual = self.sum_unspent_at(&account, None).await?;
+            if *cached != actual {
+                drifts.push(BalanceDrift {
+                    account,
+                    cached,
+                    actual: actual.into(),
+                });
+            }
+        }
+
+        Ok(drifts)
+    }
+
+    /// Computes the balances now, from a single storage snapshot so every sub-account reflects
+    /// the same committed state, or at a past instant, from the UTXOs unspent then.
+    ///
+    /// Credit limits are not versioned, past balances report the remaining credit against the
+    /// current limit.
+    async fn balances(
+        &self,
+        account: AccountId,
+        at: Option<u64>,
+    ) -> Result<BTreeMap<AssetId, Balances>, Error> {
+        let lock = (account, AccountType::Lock).into();
+
+        let (per_asset, locked) = match at {
+            None => {
+                let ledger_asset = self.asset;
+                self.storage
+                    .snapshot(move |snapshot| {
+                        let mut assets = snapshot.get_assets(account)?;
+                        assets.insert(ledger_asset);
+
+                        let mut per_asset = BTreeMap::new();
+                        for asset in assets {
+                            let mut figures = [0i128; 6];
+                            for (figure, typ) in figures.iter_mut().zip(BALANCE_SUB_ACCOUNTS) {
+                                *figure = *snapshot.get_balance(&(account, typ, asset).into())?;
+                            }
+                            let limit = snapshot
+                                .get_credit_limit(&(account, AccountType::Credit, asset).into())?;
+                            per_asset.insert(asset, (figures, limit));
+                        }
+
+                        Ok((per_asset, !snapshot.get_unspent(&lock)?.is_empty()))
+                    })
+                    .await?
+            }
+            Some(timestamp) => {
+                let mut assets = self
+                    .storage
+                    .snapshot(move |snapshot| snapshot.get_assets(account))
+                    .await?;
+                assets.insert(self.asset);
+
+                let mut per_asset = BTreeMap::new();
+
+                for asset in assets {
+                    let mut figures = [0i128; 6];
+                    for (figure, typ) in figures.iter_mut().zip(BALANCE_SUB_ACCOUNTS) {
+                        *figure = self
+                            .sum_unspent_at(&(account, typ, asset).into(), at)
+                            .await?;
+                    }
+                    let limit = self
+                        .storage
+                        .get_credit_limit(&(account, AccountType::Credit, asset).into())
+                        .await?;
+                    per_asset.insert(asset, (figures, limit));
+                }
+
+                let locked = !self
+                    .storage
+                    .get_unspent_at(&lock, timestamp)
+                    .await?
+                    .is_empty();
+                (per_asset, locked)
+            }
+        };
+
+        per_asset
             .into_iter()
-            .map(|u| *u.amount())
-            .sum::<i128>();
-        let chargeback = self
-            .storage
-            .get_unspent(&(account, AccountType::Chargeback).into(), None)
+            .map(|(asset, (figures, limit))| Ok((asset, breakdown(figures, limit, locked)?)))
+            .collect()
+    }
+
+    /// Returns the UTXOs of a sub-account unspent now, or at a past instant.
+    async fn unspent_at(&self, account: &FullAccount, at: Option<u64>) -> Result<Vec<Utxo>, Error> {
+        Ok(match at {
+            Some(timestamp) => self.storage.get_unspent_at(account, timestamp).await?,
+            None => self.storage.get_unspent(account, None).await?,
+        })
+    }
+
+    /// Returns the current balance of a sub-account, as kept by the storage layer.
+    async fn balance_of(&self, account: &FullAccount) -> Result<i128, Error> {
+        Ok(*self.storage.get_balance(account).await?)
+    }
+
+    /// Sums every UTXO of a sub-account unspent now, or at a past instant.
+    async fn sum_unspent_at(&self, account: &FullAccount, at: Option<u64>) -> Result<i128, Error> {
+        self.unspent_at(account, at)
             .await?
             .into_iter()
-            .map(|u| *u.amount())
-            .sum::<i128>();
+            .try_fold(0i128, |acc, utxo| acc.checked_add(*utxo.amount()))
+            .ok_or(Error::Math)
+    }
 
-        Ok(Balances {
-            available: main.into(),
-            disputed: disputed.into(),
-            chargeback: chargeback.into(),
-            total: main.checked_add(disputed).ok_or(Error::Math)?.into(),
-        })
+    /// Splits an amount credited to a client between its debt, its credit line and its Main
+    /// sub-account.
+    ///
+    /// Debt is recorded as negative UTXOs in the Debt sub-account, so paying it down is just a
+    /// positive output to that sub-account. Drawn credit is repaid the same way once the debt
+    /// is settled. Whatever is left goes to Main.
+    ///
+    /// The balances are read before the transaction is stored, a concurrent operation may repay
+    /// the same debt in between. Storage refuses to take a Debt or Credit sub-account above
+    /// zero, so callers run under [`Ledger::with_retry`] and split the amount again.
+    async fn credit_outputs(
+        &self,
+        account: AccountId,
+        amount: Amount,
+    ) -> Result<Vec<(FullAccount, Amount)>, Error> {
+        let mut left = *amount;
+        let mut repayments = Vec::with_capacity(2);
+
+        for typ in [AccountType::Debt, AccountType::Credit] {
+            if left <= 0 {
+                break;
+            }
+
+            let sub_account = self.full_account(account, typ);
+            let owed = self
+                .balance_of(&sub_account)
+                .await?
+                .checked_neg()
+                .ok_or(Error::Math)?;
+
+            if owed > 0 {
+                let repay = owed.min(left);
+                repayments.push((sub_account, repay.into()));
+                left = left.checked_sub(repay).ok_or(Error::Math)?;
+            }
+        }
+
+        let mut outputs = Vec::with_capacity(3);
+        if left > 0 || repayments.is_empty() {
+            outputs.push((self.full_account(account, AccountType::Main), left.into()));
+        }
+        outputs.extend(repayments);
+
+        Ok(outputs)
+    }
+
+    /// Fails with `Error::NotEnough` if spending `amount` from a Main sub-account would leave
+    /// less than the outstanding debt in its asset.
+    async fn ensure_covers_debt(&self, main: &FullAccount, amount: i128) -> Result<(), Error> {
+        let debt = self
+            .balance_of(&(main.id(), AccountType::Debt, main.asset()).into())
+            .await?;
+        let main = self.balance_of(main).await?;
+
+        if main.checked_add(debt).ok_or(Error::Math)? < amount {
+            return Err(Error::NotEnough);
+        }
+
+        Ok(())
+    }
+
+    /// Sets how much an account may overdraw through its credit line in the ledger's asset.
+    ///
+    /// Withdrawals use the available funds first and draw the rest from the Credit
+    /// sub-account, which may go negative down to the limit. Deposits repay drawn credit once
+    /// any debt is settled. Lowering the limit below the credit in use is allowed and only
+    /// blocks further draws.
+    ///
+    /// # Errors
+    /// - `Error::InvalidAmount` if the limit is negative
+    pub async fn set_credit_limit(&self, account: AccountId, limit: Amount) -> Result<(), Error> {
+        if *limit < 0 {
+            return Err(Error::InvalidAmount);
+        }
+        Ok(self
+            .storage
+            .set_credit_limit(&self.full_account(account, AccountType::Credit), limit)
+            .await?)
     }
 
     /// Withdraws funds from an account, consuming UTXOs.
     ///
-    /// Withdrawals are transactions with inputs and no outputs, effectively removing
-    /// money from the system. The UTXO model handles coin selection automatically:
+    /// Withdrawals are transactions whose only output is the account's External sub-account,
+    /// moving the money out of the client's reach. The UTXO model handles coin selection
+    /// automatically:
     /// if selected UTXOs exceed the withdrawal amount, an intermediate "exchange"
-    /// transaction creates change back to the account.
+    /// transaction creates change back to the account. Both transactions are stored
+    /// atomically, so a failed withdrawal never leaves the exchange behind.
+    ///
+    /// When the available funds fall short, the rest is drawn from the account's credit line
+    /// (see [`Ledger::set_credit_limit`]) in the same atomic batch.
+    ///
+    /// Withdrawals are idempotent by reference: replaying a withdrawal that was already
+    /// committed returns the original transaction hash ID without moving money again.
     ///
     /// # Arguments
     /// * `account` - The account to debit
@@ -268,119 +834,368 @@ where
     /// * `amount` - The amount to withdraw in the lowest denomination
     ///
     /// # Errors
-    /// Returns `Error::NotEnough` if the account has insufficient available funds.
+    /// - `Error::InvalidAmount` if the amount is zero or negative
+    /// - `Error::Locked` if the account is frozen after a chargeback
+    /// - `Error::NotEnough` if available funds and remaining credit do not cover the amount
+    /// - `Error::Storage` with `Duplicate` if the reference belongs to another transaction
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
     pub async fn withdraw(
         &self,
         account: AccountId,
-        reference: Reference,
+        reference: impl Into<Reference>,
         amount: Amount,
     ) -> Result<HashId, Error> {
-        let inputs = self
+        if *amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let reference = reference.into();
+        self.with_retry(|| self.try_withdraw(account, &reference, amount))
+            .await
+    }
+
+    /// A single attempt of [`Ledger::withdraw`].
+    async fn try_withdraw(
+        &self,
+        account: AccountId,
+        reference: &Reference,
+        amount: Amount,
+    ) -> Result<HashId, Error> {
+        if let Some(previous) = self
             .storage
-            .get_unspent(&account.into(), Some(amount))
+            .get_tx_by_reference(&self.full_account(account, AccountType::Main), reference)
+            .await?
+        {
+            let withdrawn = previous
+                .inputs()
+                .iter()
+                .try_fold(0i128, |acc, input| acc.checked_add(*input.amount()))
+                .ok_or(Error::Math)?;
+
+            return if is_withdrawal(&previous) && withdrawn == *amount {
+                // Replay of a committed withdrawal
+                Ok(previous.id())
+            } else {
+                Err(storage::Error::Duplicate.into())
+            };
+        }
+
+        if self.is_locked(account).await? {
+            return Err(Error::Locked);
+        }
+
+        // Own funds go first, whatever they do not cover is drawn from the credit line
+        let main = self
+            .balance_of(&self.full_account(account, AccountType::Main))
+            .await?;
+        let debt = self
+            .balance_of(&self.full_account(account, AccountType::Debt))
             .await?;
+        let own = main.checked_add(debt).ok_or(Error::Math)?.clamp(0, *amount);
+        let draw = amount.checked_sub(own).ok_or(Error::Math)?;
 
-        let total: i128 = inputs.iter().map(|x| *x.amount()).sum();
-        let (id, transactions) = if total < *amount {
+        if draw > 0 && draw > self.credit_remaining(account).await? {
             return Err(Error::NotEnough);
-        } else if total > *amount {
+        }
+
+        let mut inputs = if own > 0 {
+            self.select_inputs(&self.full_account(account, AccountType::Main), own.into())
+                .await?
+        } else {
+            Vec::new()
+        };
+        let mut total: i128 = inputs.iter().map(|x| *x.amount()).sum();
+        if total < own {
+            return Err(Error::NotEnough);
+        }
+
+        let mut transactions = Vec::with_capacity(3);
+        if draw > 0 {
+            // The draw credits Main against the Credit sub-account, and the withdrawal spends
+            // the new UTXO right away
+            let draw_tx = Transaction::new(
+                vec![],
+                vec![
+                    (self.full_account(account, AccountType::Main), draw.into()),
+                    (
+                        self.full_account(account, AccountType::Credit),
+                        draw.checked_neg().ok_or(Error::Math)?.into(),
+                    ),
+                ],
+                reference.to_kind(ReferenceKind::Credit),
+                None,
+            )?;
+            inputs.push(Utxo::new(
+                (draw_tx.id(), 0u8).into(),
+                draw.into(),
+                self.asset,
+            ));
+            total = total.checked_add(draw).ok_or(Error::Math)?;
+            transactions.push(draw_tx);
+        }
+
+        let id = if total > *amount {
             // The selected inputs are more than the requested amount to withdraw, so an
-            // intermediate tx is needed, since the design of ledger does not allow imbalanced
-            // transactions (except for deposit and withdrawal, but for that to happen one side if
-            // empty)
+            // intermediate tx is needed to split the change off before the withdrawal
             let exchange_tx = Transaction::new(
                 inputs,
                 vec![
-                    (account.into(), amount), // amount to the withdrawal
+                    (self.full_account(account, AccountType::Main), amount), // amount to the withdrawal
                     (
-                        account.into(),
+                        self.full_account(account, AccountType::Main),
                         total.checked_sub(*amount).ok_or(Error::Math)?.into(), // exchange
                     ),
                 ],
-                format!("Exchange for {}", reference),
+                reference.to_kind(ReferenceKind::Change),
                 None,
             )?;
             let withdrawal = Transaction::new(
-                vec![Utxo::new((exchange_tx.id(), 0u8).into(), amount)],
-                vec![],
-                reference,
+                vec![Utxo::new(
+                    (exchange_tx.id(), 0u8).into(),
+                    amount,
+                    self.asset,
+                )],
+                vec![(self.full_account(account, AccountType::External), amount)],
+                reference.clone(),
                 None,
             )?;
-            (withdrawal.id(), vec![exchange_tx, withdrawal])
+            let id = withdrawal.id();
+            transactions.extend([exchange_tx, withdrawal]);
+            id
         } else {
             // a single transaction
-            let withdrawal = Transaction::new(inputs, vec![], reference, None)?;
-            (withdrawal.id(), vec![withdrawal])
+            let withdrawal = Transaction::new(
+                inputs,
+                vec![(self.full_account(account, AccountType::External), amount)],
+                reference.clone(),
+                None,
+            )?;
+            let id = withdrawal.id();
+            transactions.push(withdrawal);
+            id
         };
 
-        for tx in transactions {
-            self.storage.store_tx(tx).await?;
+        // The draw, the change and the withdrawal are committed together or not at all
+        match self.storage.store_txs(transactions).await {
+            // A concurrent withdrawal drew on the same credit line first
+            Err(storage::Error::CreditLimit(_)) => Err(Error::NotEnough),
+            result => Ok(result.map(|_| id)?),
         }
+    }
 
-        Ok(id)
+    /// Returns how much credit the account can still draw.
+    async fn credit_remaining(&self, account: AccountId) -> Result<i128, Error> {
+        let credit_account = self.full_account(account, AccountType::Credit);
+        let limit = self.storage.get_credit_limit(&credit_account).await?;
+        let credit = self.balance_of(&credit_account).await?;
+        Ok(limit.checked_add(credit).ok_or(Error::Math)?.max(0))
     }
 
-    /// Initiates a dispute on a deposit, freezing the disputed amount.
+    /// Merges many UTXOs of a sub-account into a single one.
     ///
-    /// Only deposits (transactions with no inputs) can be disputed. The disputed
-    /// amount is moved from the Main sub-account to the Disputed sub-account,
-    /// preventing it from being spent while the dispute is being investigated.
+    /// Busy accounts accumulate lots of small UTXOs from deposits and change outputs, which
+    /// makes balances and coin selection slower. Consolidation spends up to `max_inputs` of the
+    /// smallest UTXOs into a single output to the same sub-account, so balances do not change.
     ///
-    /// # Arguments
-    /// * `account` - The account containing the disputed deposit
-    /// * `reference` - The reference of the original deposit to dispute
+    /// The transaction is stored under a [`ReferenceKind::System`] reference, telling it apart
+    /// from client movements in the account's history. Only UTXOs with a positive amount are
+    /// merged, Lock markers and negative Debt or External UTXOs are left alone. Pending
+    /// sub-accounts are never merged, each of their UTXOs belongs to a single hold.
+    ///
+    /// # Returns
+    /// The transaction hash ID, or `None` when there were fewer than two UTXOs to merge
     ///
     /// # Errors
-    /// - `Error::NotFound` if no deposit exists with the given reference
-    /// - `Error::WrongType` if the referenced transaction is not a deposit
-    pub async fn dispute(&self, account: AccountId, reference: Reference) -> Result<(), Error> {
-        let tx_to_dispute = self
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn consolidate(
+        &self,
+        account: AccountId,
+        sub_account: AccountType,
+        max_inputs: usize,
+    ) -> Result<Option<HashId>, Error> {
+        self.with_retry(|| {
+            self.try_consolidate(self.full_account(account, sub_account), max_inputs)
+        })
+        .await
+    }
+
+    /// A single attempt of [`Ledger::consolidate`].
+    async fn try_consolidate(
+        &self,
+        account: FullAccount,
+        max_inputs: usize,
+    ) -> Result<Option<HashId>, Error> {
+        if account.typ() == AccountType::Pending {
+            return Ok(None);
+        }
+
+        let inputs = self
             .storage
-            .get_tx_by_reference(&account.into(), &reference)
+            .get_unspent_sorted(&account, UtxoOrder::SmallestFirst, None)
             .await?
-            .ok_or(Error::NotFound)?;
+            .into_iter()
+            .filter(|utxo| *utxo.amount() > 0)
+            .take(max_inputs)
+            .collect::<Vec<_>>();
 
-        if !tx_to_dispute.inputs().is_empty() || tx_to_dispute.outputs().len() != 1 {
-            // Only deposits can be disputed. Deposits have no input and 1 output.
-            return Err(Error::WrongType);
+        if inputs.len() < 2 {
+            return Ok(None);
         }
 
-        let (_, disputed_amount) = tx_to_dispute
-            .outputs()
-            .first()
-            .cloned()
-            .ok_or(Error::WrongType)?;
+        let total = inputs
+            .iter()
+            .try_fold(0i128, |acc, utxo| acc.checked_add(*utxo.amount()))
+            .ok_or(Error::Math)?;
+
+        // A UTXO is spent only once, so the first input makes the reference unique
+        let first = inputs[0].id();
+        let reference = Reference::new(
+            ReferenceKind::System,
+            format!(
+                "consolidate-{}-{}",
+                first
+                    .hash_id()
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<String>(),
+                first.pos()
+            ),
+        );
 
-        // Happy path, the user still have the amount on hold, otherwise a negative deposit (or a
-        // loan) must be created to compensate
+        let consolidation =
+            Transaction::new(inputs, vec![(account, total.into())], reference, None)?;
+        let tx_id = consolidation.id();
+        self.storage.store_tx(consolidation).await?;
 
-        let inputs = self
-            .storage
-            .get_unspent(&account.into(), Some(disputed_amount))
-            .await?;
-        let available_amounts: i128 = inputs.iter().map(|f| *f.amount()).sum();
+        Ok(Some(tx_id))
+    }
 
-        let target_in_held = ((account, AccountType::Disputed).into(), disputed_amount);
-        let disputed_ref = format!("dispute:{}", reference);
+    /// Consolidates every sub-account in the ledger, see [`Ledger::consolidate`].
+    ///
+    /// # Returns
+    /// The hash IDs of the consolidation transactions, one per sub-account that had UTXOs to
+    /// merge
+    pub async fn consolidate_all(&self, max_inputs: usize) -> Result<Vec<HashId>, Error> {
+        let mut accounts = self.storage.get_accounts().await;
+        let mut consolidated = Vec::new();
+
+        while let Some(account) = accounts.try_next().await? {
+            if let Some(tx_id) = self
+                .with_retry(|| self.try_consolidate(account, max_inputs))
+                .await?
+            {
+                consolidated.push(tx_id);
+            }
+        }
 
-        let disputed_tx = if available_amounts < *disputed_amount {
-            // In this scenario their main account will go negative, but the 100% positive amount should go to dispute
-            todo!()
-        } else if available_amounts == *disputed_amount {
-            // No change
-            Transaction::new(inputs, vec![target_in_held], disputed_ref, None)?
-        } else {
-            // Move the funds to the held account and get the exchange back to the main account
-            Transaction::new(
-                inputs,
-                vec![
-                    target_in_held,
-                    (
-                        // Exchange
-                        account.into(),
-                        available_amounts
-                            .checked_sub(*disputed_amount)
-                            .ok_or(Error::Math)?
+        Ok(consolidated)
+    }
+
+    /// Initiates a dispute on a deposit or a withdrawal.
+    ///
+    /// For deposits (transactions with no inputs) the disputed amount is moved from the Main
+    /// sub-account to the Disputed sub-account, preventing it from being spent while the
+    /// dispute is being investigated.
+    ///
+    /// If the client already spent part of the deposit, whatever is left in Main is held and
+    /// the shortfall is recorded as a negative UTXO in the Debt sub-account. The full disputed
+    /// amount still lands in Disputed, and later deposits pay the debt down first.
+    ///
+    /// For withdrawals (e.g. a payout that never arrived) the withdrawn amount is provisionally
+    /// credited into the Disputed sub-account, pulled back from the External sub-account.
+    /// Resolving the dispute returns it to Main, a chargeback writes it off back to External.
+    ///
+    /// # Arguments
+    /// * `account` - The account containing the disputed transaction
+    /// * `reference` - The reference of the original deposit or withdrawal to dispute
+    ///
+    /// # Errors
+    /// - `Error::NotFound` if no transaction exists with the given reference
+    /// - `Error::WrongType` if the referenced transaction is neither a deposit nor a withdrawal
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn dispute(
+        &self,
+        account: AccountId,
+        reference: impl Into<Reference>,
+    ) -> Result<(), Error> {
+        let reference = reference.into();
+        self.with_retry(|| self.try_dispute(account, &reference))
+            .await
+    }
+
+    /// A single attempt of [`Ledger::dispute`].
+    async fn try_dispute(&self, account: AccountId, reference: &Reference) -> Result<(), Error> {
+        let target_account = self.full_account(account, AccountType::Disputed);
+        let disputed_ref = reference.to_kind(ReferenceKind::Dispute);
+
+        let disputed_amount = match self.find_disputable(account, reference).await? {
+            Disputable::Deposit(amount) => amount,
+            Disputable::Withdrawal(amount) => {
+                // The funds already left the ledger, so the client is provisionally credited
+                // while the dispute is investigated
+                let disputed_tx = Transaction::new(
+                    vec![],
+                    vec![
+                        (target_account, amount),
+                        (
+                            self.full_account(account, AccountType::External),
+                            amount.checked_neg().ok_or(Error::Math)?.into(),
+                        ),
+                    ],
+                    disputed_ref,
+                    None,
+                )?;
+                self.storage.store_tx(disputed_tx).await?;
+                return Ok(());
+            }
+        };
+
+        // Happy path, the user still have the amount on hold, otherwise a negative UTXO in the
+        // Debt sub-account is created to compensate
+
+        let inputs = self
+            .select_inputs(
+                &self.full_account(account, AccountType::Main),
+                disputed_amount,
+            )
+            .await?;
+        let available_amounts: i128 = inputs.iter().map(|f| *f.amount()).sum();
+
+        let target_in_held = (target_account, disputed_amount);
+
+        let disputed_tx = if available_amounts < *disputed_amount {
+            // In this scenario their main account will go negative, but the 100% positive amount
+            // should go to dispute. The inputs are all of Main, the shortfall becomes debt.
+            Transaction::new(
+                inputs,
+                vec![
+                    target_in_held,
+                    (
+                        self.full_account(account, AccountType::Debt),
+                        available_amounts
+                            .checked_sub(*disputed_amount)
+                            .ok_or(Error::Math)?
+                            .into(),
+                    ),
+                ],
+                disputed_ref,
+                None,
+            )?
+        } else if available_amounts == *disputed_amount {
+            // No change
+            Transaction::new(inputs, vec![target_in_held], disputed_ref, None)?
+        } else {
+            // Move the funds to the held account and get the exchange back to the main account
+            Transaction::new(
+                inputs,
+                vec![
+                    target_in_held,
+                    (
+                        // Exchange
+                        self.full_account(account, AccountType::Main),
+                        available_amounts
+                            .checked_sub(*disputed_amount)
+                            .ok_or(Error::Math)?
                             .into(),
                     ),
                 ],
@@ -394,28 +1209,95 @@ where
         Ok(())
     }
 
+    /// Looks up a client transaction by reference and classifies it for a dispute.
+    async fn find_disputable(
+        &self,
+        account: AccountId,
+        reference: &Reference,
+    ) -> Result<Disputable, Error> {
+        // A deposit that went entirely into paying a debt down is only recorded in the Debt
+        // sub-account
+        let tx = match self
+            .storage
+            .get_tx_by_reference(&self.full_account(account, AccountType::Main), reference)
+            .await?
+        {
+            Some(tx) => tx,
+            None => self
+                .storage
+                .get_tx_by_reference(&self.full_account(account, AccountType::Debt), reference)
+                .await?
+                .ok_or(Error::NotFound)?,
+        };
+
+        if tx.inputs().is_empty()
+            && !tx.outputs().is_empty()
+            && tx
+                .outputs()
+                .iter()
+                .all(|(output, _)| output.id() == account)
+        {
+            // Deposits have no input, and all their outputs go to the account (Main, and Debt
+            // when part of it paid a debt down), offset by its External sub-account.
+            let amount = tx
+                .outputs()
+                .iter()
+                .filter(|(output, _)| output.typ() != AccountType::External)
+                .try_fold(0i128, |acc, (_, amount)| acc.checked_add(**amount))
+                .ok_or(Error::Math)?;
+            Ok(Disputable::Deposit(amount.into()))
+        } else if is_withdrawal(&tx) {
+            // The reference index guarantees the inputs were spent by this account
+            let amount = tx
+                .inputs()
+                .iter()
+                .try_fold(0i128, |acc, input| acc.checked_add(*input.amount()))
+                .ok_or(Error::Math)?;
+            Ok(Disputable::Withdrawal(amount.into()))
+        } else {
+            Err(Error::WrongType)
+        }
+    }
+
     /// Resolves a dispute in favor of the account holder, releasing frozen funds.
     ///
     /// Moves funds from the Disputed sub-account back to the Main sub-account,
     /// making them available for spending again. This should be called when an
-    /// investigation determines the original deposit was legitimate.
+    /// investigation determines the original deposit was legitimate, or that a disputed
+    /// withdrawal never reached the client. Like a deposit, the restored funds pay down any
+    /// outstanding debt first.
     ///
     /// # Arguments
     /// * `account` - The account with the disputed funds
-    /// * `reference` - The reference of the original disputed deposit
+    /// * `reference` - The reference of the original disputed deposit or withdrawal
     ///
     /// # Errors
     /// - `Error::NotFound` if no dispute exists for the given reference
+    /// - `Error::DisputeClosed` if the dispute was already resolved or charged back
     /// - `Error::Internal` if disputed funds are missing (should never happen)
-    pub async fn resolve(&self, account: AccountId, reference: Reference) -> Result<(), Error> {
-        let disputed_ref = format!("dispute:{}", reference);
-        let resolved_ref = format!("resolved:{}", reference);
-        let disputed_account = (account, AccountType::Disputed).into();
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn resolve(
+        &self,
+        account: AccountId,
+        reference: impl Into<Reference>,
+    ) -> Result<(), Error> {
+        let reference = reference.into();
+        self.with_retry(|| self.try_resolve(account, &reference))
+            .await
+    }
+
+    /// A single attempt of [`Ledger::resolve`].
+    async fn try_resolve(&self, account: AccountId, reference: &Reference) -> Result<(), Error> {
+        let disputed_ref = reference.to_kind(ReferenceKind::Dispute);
+        let resolved_ref = reference.to_kind(ReferenceKind::Resolve);
+        let disputed_account = self.full_account(account, AccountType::Disputed);
         let disputed_tx = self
             .storage
             .get_tx_by_reference(&disputed_account, &disputed_ref)
             .await?
             .ok_or(Error::NotFound)?;
+        self.ensure_dispute_open(&disputed_account, reference)
+            .await?;
 
         let amount_to_restore = disputed_tx
             .outputs()
@@ -430,12 +1312,14 @@ where
             .sum::<i128>();
 
         let inputs = self
-            .storage
-            .get_unspent(&disputed_account, Some(amount_to_restore.into()))
+            .select_inputs(&disputed_account, amount_to_restore.into())
             .await?;
 
         let available_amounts: i128 = inputs.iter().map(|f| *f.amount()).sum();
-        let restore_tx = (account.into(), amount_to_restore.into());
+        // Restored funds pay down any debt before landing in Main
+        let mut restore_tx = self
+            .credit_outputs(account, amount_to_restore.into())
+            .await?;
 
         let disputed_tx = if available_amounts < amount_to_restore {
             // This cannot happen, as this account should not let money be moved, other than move it
@@ -444,25 +1328,18 @@ where
             return Err(Error::Internal);
         } else if available_amounts == amount_to_restore {
             // No change
-            Transaction::new(inputs, vec![restore_tx], resolved_ref, None)?
+            Transaction::new(inputs, restore_tx, resolved_ref, None)?
         } else {
             // Move the funds to the held account and get the exchange back to the main account
-            Transaction::new(
-                inputs,
-                vec![
-                    restore_tx,
-                    (
-                        // Exchange
-                        disputed_account,
-                        available_amounts
-                            .checked_sub(amount_to_restore)
-                            .ok_or(Error::Math)?
-                            .into(),
-                    ),
-                ],
-                resolved_ref,
-                None,
-            )?
+            restore_tx.push((
+                // Exchange
+                disputed_account,
+                available_amounts
+                    .checked_sub(amount_to_restore)
+                    .ok_or(Error::Math)?
+                    .into(),
+            ));
+            Transaction::new(inputs, restore_tx, resolved_ref, None)?
         };
 
         self.storage.store_tx(disputed_tx).await?;
@@ -476,22 +1353,43 @@ where
     /// recording that the funds have been reversed. Chargebacked funds are tracked
     /// separately for auditing purposes but are no longer accessible to the account.
     ///
+    /// The same transaction creates a zero-value marker in the Lock sub-account, which freezes
+    /// the account until an operator calls [`Ledger::unlock`].
+    ///
+    /// For a disputed withdrawal the provisional credit is written off instead: the funds go back
+    /// to the External sub-account, and the account is not locked.
+    ///
     /// # Arguments
     /// * `account` - The account with the disputed funds
-    /// * `reference` - The reference of the original disputed deposit
+    /// * `reference` - The reference of the original disputed deposit or withdrawal
     ///
     /// # Errors
     /// - `Error::NotFound` if no dispute exists for the given reference
+    /// - `Error::DisputeClosed` if the dispute was already resolved or charged back
     /// - `Error::Internal` if disputed funds are missing (should never happen)
-    pub async fn chargeback(&self, account: AccountId, reference: Reference) -> Result<(), Error> {
-        let disputed_ref = format!("dispute:{}", reference);
-        let chargeback_ref = format!("chargeback:{}", reference);
-        let disputed_account = (account, AccountType::Disputed).into();
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn chargeback(
+        &self,
+        account: AccountId,
+        reference: impl Into<Reference>,
+    ) -> Result<(), Error> {
+        let reference = reference.into();
+        self.with_retry(|| self.try_chargeback(account, &reference))
+            .await
+    }
+
+    /// A single attempt of [`Ledger::chargeback`].
+    async fn try_chargeback(&self, account: AccountId, reference: &Reference) -> Result<(), Error> {
+        let disputed_ref = reference.to_kind(ReferenceKind::Dispute);
+        let chargeback_ref = reference.to_kind(ReferenceKind::Chargeback);
+        let disputed_account = self.full_account(account, AccountType::Disputed);
         let disputed_tx = self
             .storage
             .get_tx_by_reference(&disputed_account, &disputed_ref)
             .await?
             .ok_or(Error::NotFound)?;
+        self.ensure_dispute_open(&disputed_account, reference)
+            .await?;
 
         let amount_to_chargeback = disputed_tx
             .outputs()
@@ -506,165 +1404,772 @@ where
             .sum::<i128>();
 
         let inputs = self
-            .storage
-            .get_unspent(&disputed_account, Some(amount_to_chargeback.into()))
+            .select_inputs(&disputed_account, amount_to_chargeback.into())
             .await?;
 
         let available_amounts: i128 = inputs.iter().map(|f| *f.amount()).sum();
-        let chargeback_tx = (
-            (account, AccountType::Chargeback).into(),
-            amount_to_chargeback.into(),
-        );
+        let mut outputs = match self.find_disputable(account, reference).await? {
+            Disputable::Deposit(_) => vec![
+                (
+                    self.full_account(account, AccountType::Chargeback),
+                    amount_to_chargeback.into(),
+                ),
+                ((account, AccountType::Lock).into(), 0.into()),
+            ],
+            // The provisional credit is written off
+            Disputable::Withdrawal(_) => vec![(
+                self.full_account(account, AccountType::External),
+                amount_to_chargeback.into(),
+            )],
+        };
 
-        let chargeback_tx = if available_amounts < amount_to_chargeback {
+        if available_amounts < amount_to_chargeback {
             // This cannot happen, as this account should not let money be moved, other than move it
             // back to the main when the dispute has been resolved or to locked if it was a
             // chargeback
             return Err(Error::Internal);
-        } else if available_amounts == amount_to_chargeback {
-            // No change
-            Transaction::new(inputs, vec![chargeback_tx], chargeback_ref, None)?
-        } else {
+        } else if available_amounts > amount_to_chargeback {
             // Move the funds to the held account and get the exchange back to the main account
-            Transaction::new(
-                inputs,
-                vec![
-                    chargeback_tx,
-                    (
-                        // Exchange
-                        disputed_account,
-                        available_amounts
-                            .checked_sub(amount_to_chargeback)
-                            .ok_or(Error::Math)?
-                            .into(),
-                    ),
-                ],
-                chargeback_ref,
-                None,
-            )?
-        };
+            outputs.push((
+                // Exchange
+                disputed_account,
+                available_amounts
+                    .checked_sub(amount_to_chargeback)
+                    .ok_or(Error::Math)?
+                    .into(),
+            ));
+        }
+
+        let chargeback_tx = Transaction::new(inputs, outputs, chargeback_ref, None)?;
 
         self.storage.store_tx(chargeback_tx).await?;
 
         Ok(())
     }
 
-    /// Transfers funds between accounts (not yet implemented).
+    /// Fails with `Error::DisputeClosed` if the dispute of `reference` was already resolved or
+    /// charged back.
     ///
-    /// This will enable peer-to-peer transfers by consuming UTXOs from the source
-    /// account and creating new UTXOs in the destination account within a single
-    /// atomic transaction.
-    ///
-    /// # Panics
-    /// Currently unimplemented and will panic if called.
-    pub fn movement(&self, _from: AccountId, _to: AccountId, _amount: Amount) {
-        todo!()
+    /// Both spend from the Disputed sub-account, so their references are indexed under it.
+    /// Without this check a late resolve would release Disputed funds held for another open
+    /// dispute.
+    async fn ensure_dispute_open(
+        &self,
+        disputed_account: &FullAccount,
+        reference: &Reference,
+    ) -> Result<(), Error> {
+        for kind in [ReferenceKind::Resolve, ReferenceKind::Chargeback] {
+            if self
+                .storage
+                .get_tx_by_reference(disputed_account, &reference.to_kind(kind))
+                .await?
+                .is_some()
+            {
+                return Err(Error::DisputeClosed);
+            }
+        }
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Returns whether the account is frozen.
+    ///
+    /// An account is locked while its Lock sub-account holds any unspent marker, which is
+    /// created by every chargeback and consumed by [`Ledger::unlock`]. Markers always belong to
+    /// the default asset, so the lock applies to every asset.
+    pub async fn is_locked(&self, account: AccountId) -> Result<bool, Error> {
+        Ok(!self
+            .storage
+            .get_unspent(&(account, AccountType::Lock).into(), None)
+            .await?
+            .is_empty())
+    }
 
-    async fn assert_balance(
-        ledger: &Ledger<Memory>,
+    /// Unfreezes an account locked by a chargeback.
+    ///
+    /// This is an administrative operation. It spends every lock marker of the account in a
+    /// single transaction, so the unlock is stored in the ledger like any other movement and can
+    /// be audited later. The chargeback funds themselves are not touched.
+    ///
+    /// # Arguments
+    /// * `account` - The account to unlock
+    /// * `reference` - Unique identifier for this unlock (e.g., the support ticket)
+    ///
+    /// # Returns
+    /// The transaction hash ID on success
+    ///
+    /// # Errors
+    /// - `Error::NotFound` if the account is not locked
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn unlock(
+        &self,
         account: AccountId,
-        main: i128,
-        disputed: i128,
-    ) {
-        let balances = ledger
-            .get_balances(account)
+        reference: impl Into<Reference>,
+    ) -> Result<HashId, Error> {
+        // Unlocking is an operator action, kept apart from client references
+        let reference = reference.into().to_kind(ReferenceKind::System);
+        self.with_retry(|| self.try_unlock(account, &reference))
             .await
-            .expect("get_balances should succeed");
-        assert_eq!(*balances.available, main, "main balance mismatch");
-        assert_eq!(*balances.disputed, disputed, "disputed balance mismatch");
-        assert_eq!(*balances.total, main + disputed, "total balance mismatch");
     }
 
-    #[tokio::test]
-    async fn test_deposit_creates_balance() {
-        let ledger = Ledger::default();
-        let account_id: AccountId = 1;
+    /// A single attempt of [`Ledger::unlock`].
+    async fn try_unlock(&self, account: AccountId, reference: &Reference) -> Result<HashId, Error> {
+        let markers = self
+            .storage
+            .get_unspent(&(account, AccountType::Lock).into(), None)
+            .await?;
 
-        let tx_id = ledger
-            .deposit(account_id, "deposit-1".to_string(), 100.into())
-            .await
-            .expect("deposit should succeed");
+        if markers.is_empty() {
+            return Err(Error::NotFound);
+        }
 
-        // Verify the transaction was created (non-zero hash)
-        assert_ne!(tx_id, [0u8; 32]);
+        let unlock_tx = Transaction::new(markers, vec![], reference.clone(), None)?;
+        let tx_id = unlock_tx.id();
+        self.storage.store_tx(unlock_tx).await?;
 
-        // Verify balance after deposit
-        assert_balance(&ledger, account_id, 100, 0).await;
+        Ok(tx_id)
     }
 
-    #[tokio::test]
-    async fn test_deposit_and_withdraw_exact_amount() {
-        let ledger = Ledger::default();
-        let account_id: AccountId = 1;
-
-        // Deposit 100
-        ledger
-            .deposit(account_id, "deposit-1".to_string(), 100.into())
+    /// Reserves funds for a later capture, like a card authorisation.
+    ///
+    /// The amount is moved from the Main sub-account into the Pending sub-account, where it
+    /// cannot be spent, with any change going back to Main. The hold is later settled with
+    /// [`Ledger::capture`] or released with [`Ledger::void`]. When `expires_at` (microseconds
+    /// since the Unix epoch) is given the hold can no longer be captured from that instant on,
+    /// and [`Ledger::release_expired`] voids it.
+    ///
+    /// # Arguments
+    /// * `account` - The account to reserve funds from
+    /// * `reference` - Unique identifier for this hold
+    /// * `amount` - The amount to reserve in the lowest denomination
+    /// * `expires_at` - Optional instant after which the hold expires
+    ///
+    /// # Errors
+    /// - `Error::InvalidAmount` if the amount is zero or negative
+    /// - `Error::Locked` if the account is frozen after a chargeback
+    /// - `Error::NotEnough` if the account has insufficient available funds
+    /// - `Error::Storage` with `Duplicate` if the reference was already used
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn authorize(
+        &self,
+        account: AccountId,
+        reference: impl Into<Reference>,
+        amount: Amount,
+        expires_at: Option<u64>,
+    ) -> Result<HashId, Error> {
+        let reference = reference.into();
+        self.with_retry(|| self.try_authorize(account, &reference, amount, expires_at))
             .await
-            .expect("deposit should succeed");
+    }
 
-        // Verify balance after deposit
-        assert_balance(&ledger, account_id, 100, 0).await;
+    /// A single attempt of [`Ledger::authorize`].
+    async fn try_authorize(
+        &self,
+        account: AccountId,
+        reference: &Reference,
+        amount: Amount,
+        expires_at: Option<u64>,
+    ) -> Result<HashId, Error> {
+        if *amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
 
-        // Withdraw exactly 100
-        let tx_id = ledger
-            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
-            .await
-            .expect("exact withdrawal should succeed");
+        if self.is_locked(account).await? {
+            return Err(Error::Locked);
+        }
 
-        assert_ne!(tx_id, [0u8; 32]);
+        self.ensure_covers_debt(&self.full_account(account, AccountType::Main), *amount)
+            .await?;
 
-        // Verify balance after withdrawal
-        assert_balance(&ledger, account_id, 0, 0).await;
-    }
+        let inputs = self
+            .select_inputs(&self.full_account(account, AccountType::Main), amount)
+            .await?;
+        let total = inputs
+            .iter()
+            .try_fold(0i128, |acc, utxo| acc.checked_add(*utxo.amount()))
+            .ok_or(Error::Math)?;
+        if total < *amount {
+            return Err(Error::NotEnough);
+        }
 
-    #[tokio::test]
-    async fn test_withdraw_partial_amount() {
-        let ledger = Ledger::default();
-        let account_id: AccountId = 1;
+        let mut outputs = vec![(self.full_account(account, AccountType::Pending), amount)];
+        if total > *amount {
+            outputs.push((
+                self.full_account(account, AccountType::Main),
+                total.checked_sub(*amount).ok_or(Error::Math)?.into(),
+            ));
+        }
 
-        // Deposit 100
-        ledger
-            .deposit(account_id, "deposit-1".to_string(), 100.into())
-            .await
-            .expect("deposit should succeed");
+        let mut hold = Transaction::new(inputs, outputs, reference.clone(), None)?;
+        hold.set_expires_at(expires_at);
+        let tx_id = hold.id();
+        self.storage.store_tx(hold).await?;
 
-        // Verify balance after deposit
-        assert_balance(&ledger, account_id, 100, 0).await;
+        Ok(tx_id)
+    }
 
-        // Withdraw 60 (partial)
-        let tx_id = ledger
-            .withdraw(account_id, "withdraw-1".to_string(), 60.into())
+    /// Settles an authorisation hold, fully or partially.
+    ///
+    /// The captured amount leaves the ledger through the account's External sub-account, like
+    /// a withdrawal. Whatever is not captured is released back to the account and, like a
+    /// deposit, pays down any outstanding debt or drawn credit first. A hold is captured once.
+    ///
+    /// # Arguments
+    /// * `account` - The account holding the funds
+    /// * `reference` - The reference the hold was authorised with
+    /// * `amount` - The amount to capture, at most the held amount
+    ///
+    /// # Errors
+    /// - `Error::NotFound` if no hold exists for the given reference
+    /// - `Error::HoldClosed` if the hold was already captured or voided
+    /// - `Error::HoldExpired` if the hold expired
+    /// - `Error::InvalidAmount` if the amount is zero, negative or more than the held amount
+    /// - `Error::Locked` if the account is frozen after a chargeback
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn capture(
+        &self,
+        account: AccountId,
+        reference: impl Into<Reference>,
+        amount: Amount,
+    ) -> Result<HashId, Error> {
+        let reference = reference.into();
+        self.with_retry(|| self.try_capture(account, &reference, amount))
             .await
-            .expect("partial withdrawal should succeed");
+    }
 
-        assert_ne!(tx_id, [0u8; 32]);
+    /// A single attempt of [`Ledger::capture`].
+    async fn try_capture(
+        &self,
+        account: AccountId,
+        reference: &Reference,
+        amount: Amount,
+    ) -> Result<HashId, Error> {
+        let (hold, held) = self.find_hold(account, reference).await?;
+        if *amount <= 0 || *amount > *held.amount() {
+            return Err(Error::InvalidAmount);
+        }
 
-        // Verify balance after first withdrawal
-        assert_balance(&ledger, account_id, 40, 0).await;
+        if self.is_locked(account).await? {
+            return Err(Error::Locked);
+        }
 
-        // Should be able to withdraw remaining 40
-        let tx_id2 = ledger
-            .withdraw(account_id, "withdraw-2".to_string(), 40.into())
-            .await
-            .expect("withdrawing remaining balance should succeed");
+        let mut outputs = vec![(self.full_account(account, AccountType::External), amount)];
+        let released = held.amount().checked_sub(*amount).ok_or(Error::Math)?;
+        if released > 0 {
+            outputs.extend(self.credit_outputs(account, released.into()).await?);
+        }
 
-        assert_ne!(tx_id2, [0u8; 32]);
+        let capture = Transaction::new(
+            vec![held],
+            outputs,
+            reference.to_kind(ReferenceKind::Capture),
+            None,
+        )?;
+        if hold
+            .expires_at()
+            .is_some_and(|expires_at| capture.timestamp() >= expires_at)
+        {
+            return Err(Error::HoldExpired);
+        }
 
-        // Verify balance after second withdrawal
-        assert_balance(&ledger, account_id, 0, 0).await;
-    }
+        let tx_id = capture.id();
+        self.storage.store_tx(capture).await?;
 
-    #[tokio::test]
-    async fn test_over_withdrawal_not_possible() {
-        let ledger = Ledger::default();
-        let account_id: AccountId = 1;
+        Ok(tx_id)
+    }
+
+    /// Releases an authorisation hold without capturing it.
+    ///
+    /// The held funds go back to the account and, like a deposit, pay down any outstanding
+    /// debt or drawn credit first. Expired holds can still be voided.
+    ///
+    /// # Errors
+    /// - `Error::NotFound` if no hold exists for the given reference
+    /// - `Error::HoldClosed` if the hold was already captured or voided
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn void(
+        &self,
+        account: AccountId,
+        reference: impl Into<Reference>,
+    ) -> Result<HashId, Error> {
+        let reference = reference.into();
+        self.with_retry(|| self.try_void(account, &reference)).await
+    }
+
+    /// A single attempt of [`Ledger::void`].
+    async fn try_void(&self, account: AccountId, reference: &Reference) -> Result<HashId, Error> {
+        let (_, held) = self.find_hold(account, reference).await?;
+
+        let release = Transaction::new(
+            vec![held],
+            self.credit_outputs(account, held.amount()).await?,
+            reference.to_kind(ReferenceKind::Void),
+            None,
+        )?;
+        let tx_id = release.id();
+        self.storage.store_tx(release).await?;
+
+        Ok(tx_id)
+    }
+
+    /// Voids every expired authorisation hold in the ledger.
+    ///
+    /// # Returns
+    /// The hash IDs of the void transactions
+    pub async fn release_expired(&self) -> Result<Vec<HashId>, Error> {
+        let now = transaction::now();
+        let mut accounts = self.storage.get_accounts().await;
+        let mut released = Vec::new();
+
+        while let Some(account) = accounts.try_next().await? {
+            if account.typ() != AccountType::Pending {
+                continue;
+            }
+
+            for utxo in self.storage.get_unspent(&account, None).await? {
+                let hold = self
+                    .storage
+                    .get_tx(&utxo.id().hash_id())
+                    .await?
+                    .ok_or(Error::Internal)?;
+                if hold.expires_at().is_none_or(|expires_at| expires_at > now) {
+                    continue;
+                }
+
+                match self
+                    .for_asset(account.asset())
+                    .void(account.id(), hold.reference())
+                    .await
+                {
+                    Ok(tx_id) => released.push(tx_id),
+                    // Captured or voided concurrently
+                    Err(Error::HoldClosed) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Ok(released)
+    }
+
+    /// Looks up an open authorisation hold, returning it with the UTXO holding its funds.
+    async fn find_hold(
+        &self,
+        account: AccountId,
+        reference: &Reference,
+    ) -> Result<(Transaction, Utxo), Error> {
+        let pending = self.full_account(account, AccountType::Pending);
+        let hold = self
+            .storage
+            .get_tx_by_reference(&pending, reference)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        let (pos, amount) = hold
+            .outputs()
+            .iter()
+            .enumerate()
+            .find(|(_, (output, _))| *output == pending)
+            .map(|(pos, (_, amount))| (pos, *amount))
+            .ok_or(Error::WrongType)?;
+
+        for kind in [ReferenceKind::Capture, ReferenceKind::Void] {
+            if self
+                .storage
+                .get_tx_by_reference(&pending, &reference.to_kind(kind))
+                .await?
+                .is_some()
+            {
+                return Err(Error::HoldClosed);
+            }
+        }
+
+        let pos = pos.try_into().map_err(|_| Error::Internal)?;
+        let held = Utxo::new((hold.id(), pos).into(), amount, pending.asset());
+        Ok((hold, held))
+    }
+
+    /// Transfers funds between two accounts in a single atomic transaction.
+    ///
+    /// UTXOs are selected from the source Main sub-account to cover the amount. The resulting
+    /// transaction spends them and creates an output for the destination and, when the selected
+    /// inputs exceed the amount, a change output back to the source. Both legs live in the same
+    /// transaction, so either the whole movement is committed or nothing is.
+    ///
+    /// This is the two-party case of [`Ledger::transfer`].
+    ///
+    /// # Arguments
+    /// * `from` - The account to debit
+    /// * `to` - The account to credit
+    /// * `reference` - Unique identifier for this movement, recorded for every account involved
+    /// * `amount` - The amount to move in the lowest denomination
+    ///
+    /// # Returns
+    /// The transaction hash ID on success
+    ///
+    /// # Errors
+    /// - `Error::InvalidAmount` if the amount is not positive
+    /// - `Error::Locked` if either account is frozen after a chargeback
+    /// - `Error::NotEnough` if the source account has insufficient available funds
+    /// - `Error::Storage` with `Duplicate` if the reference was already used
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn movement(
+        &self,
+        from: AccountId,
+        to: AccountId,
+        reference: impl Into<Reference>,
+        amount: Amount,
+    ) -> Result<HashId, Error> {
+        self.transfer(
+            reference,
+            vec![(self.full_account(from, AccountType::Main), amount)],
+            vec![(self.full_account(to, AccountType::Main), amount)],
+        )
+        .await
+    }
+
+    /// Commits a balanced set of debits and credits across many accounts as one transaction.
+    ///
+    /// This is the general form of [`Ledger::movement`]: any number of Main sub-accounts, in any
+    /// asset, can be debited or credited, e.g. a purchase that debits a buyer and credits a
+    /// merchant, a platform fee account and a tax account at once. The other sub-accounts are
+    /// internal to the ledger, only its own operations move funds in and out of them.
+    ///
+    /// Debits to the same account are merged, and UTXOs are selected for each debited account
+    /// independently. Every debited account whose selected UTXOs exceed its debit receives a
+    /// single change output. All legs end up in one `Transaction`, so the set is committed or
+    /// rejected as a whole.
+    ///
+    /// # Arguments
+    /// * `reference` - Unique identifier for this transfer, recorded for every account involved
+    /// * `debits` - Accounts to take funds from and how much
+    /// * `credits` - Accounts to give funds to and how much
+    ///
+    /// # Returns
+    /// The transaction hash ID on success
+    ///
+    /// # Errors
+    /// - `Error::InvalidAmount` if a leg is missing or any amount is not positive
+    /// - `Error::InvalidAccount` if a leg is not a Main sub-account
+    /// - `Error::Tx` with `Imbalanced` if debits and credits of an asset do not add up to the
+    ///   same total
+    /// - `Error::Locked` if any account involved is frozen after a chargeback
+    /// - `Error::NotEnough` if any debited account has insufficient funds, including a Main
+    ///   sub-account that would no longer cover its outstanding debt
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn transfer(
+        &self,
+        reference: impl Into<Reference>,
+        debits: Vec<(FullAccount, Amount)>,
+        credits: Vec<(FullAccount, Amount)>,
+    ) -> Result<HashId, Error> {
+        let reference = reference.into();
+        if debits.is_empty() || credits.is_empty() {
+            return Err(Error::InvalidAmount);
+        }
+
+        // Crediting a Lock sub-account would freeze someone else's account, debiting a Disputed
+        // one would release funds under investigation, and so on
+        if let Some((account, _)) = debits
+            .iter()
+            .chain(credits.iter())
+            .find(|(account, _)| account.typ() != AccountType::Main)
+        {
+            return Err(Error::InvalidAccount(*account));
+        }
+
+        // Merge debits per account so each account goes through coin selection exactly once.
+        // A BTreeMap keeps the order of the change outputs deterministic.
+        let mut to_debit = BTreeMap::<FullAccount, i128>::new();
+        for (account, amount) in debits {
+            if *amount <= 0 {
+                return Err(Error::InvalidAmount);
+            }
+            let entry = to_debit.entry(account).or_default();
+            *entry = entry.checked_add(*amount).ok_or(Error::Math)?;
+        }
+
+        // Debits and credits of each asset must match on their own
+        let mut totals = BTreeMap::<AssetId, i128>::new();
+        for (account, amount) in to_debit.iter() {
+            let total = totals.entry(account.asset()).or_default();
+            *total = total.checked_add(*amount).ok_or(Error::Math)?;
+        }
+        for (account, amount) in credits.iter() {
+            if **amount <= 0 {
+                return Err(Error::InvalidAmount);
+            }
+            let total = totals.entry(account.asset()).or_default();
+            *total = total.checked_sub(**amount).ok_or(Error::Math)?;
+        }
+
+        if totals.values().any(|total| *total != 0) {
+            return Err(transaction::Error::Imbalanced.into());
+        }
+
+        self.with_retry(|| self.try_transfer(&reference, &to_debit, &credits))
+            .await
+    }
+
+    /// A single attempt of [`Ledger::transfer`], once the legs are validated and merged.
+    async fn try_transfer(
+        &self,
+        reference: &Reference,
+        to_debit: &BTreeMap<FullAccount, i128>,
+        credits: &[(FullAccount, Amount)],
+    ) -> Result<HashId, Error> {
+        let involved = to_debit
+            .keys()
+            .chain(credits.iter().map(|(account, _)| account))
+            .map(|account| account.id())
+            .collect::<BTreeSet<_>>();
+        for account in involved {
+            if self.is_locked(account).await? {
+                return Err(Error::Locked);
+            }
+        }
+
+        let mut inputs = Vec::new();
+        let mut outputs = credits.to_vec();
+
+        for (&account, &amount) in to_debit {
+            if account.typ() == AccountType::Main {
+                self.ensure_covers_debt(&account, amount).await?;
+            }
+
+            let selected = self.select_inputs(&account, amount.into()).await?;
+            let total: i128 = selected.iter().map(|x| *x.amount()).sum();
+
+            if total < amount {
+                return Err(Error::NotEnough);
+            }
+
+            if total > amount {
+                outputs.push((
+                    // Exchange
+                    account,
+                    total.checked_sub(amount).ok_or(Error::Math)?.into(),
+                ));
+            }
+
+            inputs.extend(selected);
+        }
+
+        let transfer = Transaction::new(inputs, outputs, reference.clone(), None)?;
+        let tx_id = transfer.id();
+        self.storage.store_tx(transfer).await?;
+
+        Ok(tx_id)
+    }
+
+    /// Converts funds of an account from the ledger's asset into another asset.
+    ///
+    /// The account sells `amount` of the ledger's asset to a house account, and buys from it
+    /// the amount of `to` given by `rate`. Both legs live in a single transaction, balanced per
+    /// asset: the sold asset moves from the account's Main sub-account to the house's, the
+    /// bought asset from the house's Main sub-account to the account, paying down any debt or
+    /// drawn credit in that asset first. The rate is recorded in the transaction.
+    ///
+    /// Conversions are exact, an amount that does not convert into a whole amount of `to` is
+    /// rejected rather than rounded. Like every movement, the reference is recorded for the
+    /// house account as well.
+    ///
+    /// # Arguments
+    /// * `account` - The account converting funds
+    /// * `house` - The counterparty account, holding the liquidity of both assets
+    /// * `reference` - Unique identifier for this exchange
+    /// * `amount` - The amount to sell, in the lowest denomination of the ledger's asset
+    /// * `to` - The asset to buy
+    /// * `rate` - How much of `to` each unit of the ledger's asset buys
+    ///
+    /// # Returns
+    /// The transaction hash ID on success
+    ///
+    /// # Errors
+    /// - `Error::InvalidAmount` if the amount or either side of the rate is not positive, or
+    ///   `to` is the ledger's asset
+    /// - `Error::InexactConversion` if the converted amount is not a whole amount
+    /// - `Error::Locked` if either account is frozen after a chargeback
+    /// - `Error::NotEnough` if the account cannot cover the amount, or the house the converted
+    ///   amount
+    /// - `Error::Storage` with `Duplicate` if the reference was already used
+    /// - `Error::Contention` if concurrent operations kept spending the selected funds
+    pub async fn exchange(
+        &self,
+        account: AccountId,
+        house: AccountId,
+        reference: impl Into<Reference>,
+        amount: Amount,
+        to: AssetId,
+        rate: Rate,
+    ) -> Result<HashId, Error> {
+        let reference = reference.into();
+        if *amount <= 0 || *rate.base <= 0 || *rate.quote <= 0 || to == self.asset {
+            return Err(Error::InvalidAmount);
+        }
+
+        self.with_retry(|| self.try_exchange(account, house, &reference, amount, to, rate))
+            .await
+    }
+
+    /// A single attempt of [`Ledger::exchange`].
+    async fn try_exchange(
+        &self,
+        account: AccountId,
+        house: AccountId,
+        reference: &Reference,
+        amount: Amount,
+        to: AssetId,
+        rate: Rate,
+    ) -> Result<HashId, Error> {
+        let converted = rate.convert(*amount).ok_or(Error::InexactConversion)?;
+
+        for id in [account, house] {
+            if self.is_locked(id).await? {
+                return Err(Error::Locked);
+            }
+        }
+
+        let bought = self.for_asset(to);
+        let mut inputs = Vec::new();
+        let mut outputs = vec![(self.full_account(house, AccountType::Main), amount)];
+        outputs.extend(bought.credit_outputs(account, converted.into()).await?);
+
+        // Each side pays its leg from its Main sub-account in the asset it sells
+        for (payer, debit) in [
+            (self.full_account(account, AccountType::Main), *amount),
+            (bought.full_account(house, AccountType::Main), converted),
+        ] {
+            self.ensure_covers_debt(&payer, debit).await?;
+
+            let selected = self.select_inputs(&payer, debit.into()).await?;
+            let total = selected
+                .iter()
+                .try_fold(0i128, |acc, utxo| acc.checked_add(*utxo.amount()))
+                .ok_or(Error::Math)?;
+            if total < debit {
+                return Err(Error::NotEnough);
+            }
+
+            if total > debit {
+                outputs.push((payer, total.checked_sub(debit).ok_or(Error::Math)?.into()));
+            }
+
+            inputs.extend(selected);
+        }
+
+        let mut exchange = Transaction::new(inputs, outputs, reference.clone(), None)?;
+        exchange.set_rate(Some(rate));
+        let tx_id = exchange.id();
+        self.storage.store_tx(exchange).await?;
+
+        Ok(tx_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn assert_balance(
+        ledger: &Ledger<Memory>,
+        account: AccountId,
+        main: i128,
+        disputed: i128,
+    ) {
+        let balances = ledger
+            .get_balances(account)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, main, "main balance mismatch");
+        assert_eq!(*balances.disputed, disputed, "disputed balance mismatch");
+        assert_eq!(*balances.total, main + disputed, "total balance mismatch");
+    }
+
+    #[tokio::test]
+    async fn test_deposit_creates_balance() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        let tx_id = ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        // Verify the transaction was created (non-zero hash)
+        assert_ne!(tx_id, [0u8; 32]);
+
+        // Verify balance after deposit
+        assert_balance(&ledger, account_id, 100, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_deposit_and_withdraw_exact_amount() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        // Deposit 100
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        // Verify balance after deposit
+        assert_balance(&ledger, account_id, 100, 0).await;
+
+        // Withdraw exactly 100
+        let tx_id = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("exact withdrawal should succeed");
+
+        assert_ne!(tx_id, [0u8; 32]);
+
+        // Verify balance after withdrawal
+        assert_balance(&ledger, account_id, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_partial_amount() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        // Deposit 100
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        // Verify balance after deposit
+        assert_balance(&ledger, account_id, 100, 0).await;
+
+        // Withdraw 60 (partial)
+        let tx_id = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 60.into())
+            .await
+            .expect("partial withdrawal should succeed");
+
+        assert_ne!(tx_id, [0u8; 32]);
+
+        // Verify balance after first withdrawal
+        assert_balance(&ledger, account_id, 40, 0).await;
+
+        // Should be able to withdraw remaining 40
+        let tx_id2 = ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 40.into())
+            .await
+            .expect("withdrawing remaining balance should succeed");
+
+        assert_ne!(tx_id2, [0u8; 32]);
+
+        // Verify balance after second withdrawal
+        assert_balance(&ledger, account_id, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_over_withdrawal_not_possible() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
 
         // Deposit 100
         ledger
@@ -898,7 +2403,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_dispute_transfer_fails_wrong_type() {
+    async fn test_dispute_exchange_not_reachable_by_client() {
         let ledger = Ledger::default();
         let account_id: AccountId = 1;
 
@@ -917,13 +2422,25 @@ mod tests {
         // Verify balance after withdrawal
         assert_balance(&ledger, account_id, 50, 0).await;
 
-        // Try to dispute the exchange transaction (has inputs, so it's not a deposit)
-        // The exchange tx has reference "Exchange for withdraw-1"
+        // The exchange transaction has a change reference, which lives in its own namespace
+        // and cannot be addressed with a client reference
         let result = ledger
             .dispute(account_id, "Exchange for withdraw-1".to_string())
             .await;
 
-        assert!(matches!(result, Err(Error::WrongType)));
+        assert!(matches!(result, Err(Error::NotFound)));
+
+        let exchange = ledger
+            .storage
+            .get_tx_by_reference(
+                &account_id.into(),
+                &Reference::from("withdraw-1").to_kind(ReferenceKind::Change),
+            )
+            .await
+            .expect("get_tx_by_reference should succeed")
+            .expect("exchange should be indexed under its change reference");
+        assert_eq!(exchange.reference().kind(), ReferenceKind::Change);
+        assert_eq!(exchange.reference().id(), "withdraw-1");
     }
 
     #[tokio::test]
@@ -1084,4 +2601,2427 @@ mod tests {
         sorted_actual.sort();
         assert_eq!(sorted_actual, sorted_expected);
     }
+
+    #[tokio::test]
+    async fn test_movement_with_change() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        let tx_id = ledger
+            .movement(1, 2, "move-1".to_string(), 30.into())
+            .await
+            .expect("movement should succeed");
+
+        assert_ne!(tx_id, [0u8; 32]);
+
+        // Both legs are part of the same transaction
+        assert_balance(&ledger, 1, 70, 0).await;
+        assert_balance(&ledger, 2, 30, 0).await;
+
+        let tx = ledger
+            .storage
+            .get_tx_by_reference(&2.into(), &"move-1".into())
+            .await
+            .expect("get_tx_by_reference should succeed")
+            .expect("movement should be found by reference");
+        assert_eq!(tx.id(), tx_id);
+        assert_eq!(tx.outputs().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_movement_exact_amount() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 40.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(1, "deposit-2".to_string(), 60.into())
+            .await
+            .expect("deposit should succeed");
+
+        ledger
+            .movement(1, 2, "move-1".to_string(), 100.into())
+            .await
+            .expect("movement of the full balance should succeed");
+
+        assert_balance(&ledger, 1, 0, 0).await;
+        assert_balance(&ledger, 2, 100, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_movement_not_enough_funds() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+
+        let result = ledger.movement(1, 2, "move-1".to_string(), 51.into()).await;
+        assert!(matches!(result, Err(Error::NotEnough)));
+
+        // Nothing moved
+        assert_balance(&ledger, 1, 50, 0).await;
+        assert_balance(&ledger, 2, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_movement_rejects_non_positive_amount() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+
+        for amount in [0, -10] {
+            let result = ledger
+                .movement(1, 2, "move-1".to_string(), amount.into())
+                .await;
+            assert!(matches!(result, Err(Error::InvalidAmount)));
+        }
+
+        assert_balance(&ledger, 1, 50, 0).await;
+        assert_balance(&ledger, 2, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_movement_duplicate_reference_rejected() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        ledger
+            .movement(1, 2, "move-1".to_string(), 10.into())
+            .await
+            .expect("first movement should succeed");
+
+        // Replaying the same reference must not move money again
+        let result = ledger.movement(1, 2, "move-1".to_string(), 10.into()).await;
+        assert!(matches!(
+            result,
+            Err(Error::Storage(storage::Error::Duplicate))
+        ));
+
+        assert_balance(&ledger, 1, 90, 0).await;
+        assert_balance(&ledger, 2, 10, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_multi_leg_purchase() {
+        let ledger = Ledger::default();
+        let (buyer, merchant, fee, tax): (AccountId, AccountId, AccountId, AccountId) =
+            (1, 2, 3, 4);
+
+        ledger
+            .deposit(buyer, "deposit-1".to_string(), 60.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(buyer, "deposit-2".to_string(), 60.into())
+            .await
+            .expect("deposit should succeed");
+
+        let tx_id = ledger
+            .transfer(
+                "purchase-1".to_string(),
+                vec![(buyer.into(), 100.into())],
+                vec![
+                    (merchant.into(), 85.into()),
+                    (fee.into(), 10.into()),
+                    (tax.into(), 5.into()),
+                ],
+            )
+            .await
+            .expect("balanced transfer should succeed");
+
+        assert_balance(&ledger, buyer, 20, 0).await;
+        assert_balance(&ledger, merchant, 85, 0).await;
+        assert_balance(&ledger, fee, 10, 0).await;
+        assert_balance(&ledger, tax, 5, 0).await;
+
+        // All legs, including the buyer's change, are in a single transaction
+        let tx = ledger
+            .storage
+            .get_tx_by_reference(&merchant.into(), &"purchase-1".into())
+            .await
+            .expect("get_tx_by_reference should succeed")
+            .expect("transfer should be found by reference");
+        assert_eq!(tx.id(), tx_id);
+        assert_eq!(tx.outputs().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_many_debits_with_change_per_account() {
+        let ledger = Ledger::default();
+
+        for (account, amount) in [(1, 50), (2, 30)] {
+            ledger
+                .deposit(account, format!("deposit-{}", account), amount.into())
+                .await
+                .expect("deposit should succeed");
+        }
+
+        ledger
+            .transfer(
+                "split-1".to_string(),
+                vec![
+                    (1.into(), 20.into()),
+                    (2.into(), 10.into()),
+                    // Debits to the same account are merged
+                    (1.into(), 5.into()),
+                ],
+                vec![(3.into(), 35.into())],
+            )
+            .await
+            .expect("transfer should succeed");
+
+        assert_balance(&ledger, 1, 25, 0).await;
+        assert_balance(&ledger, 2, 20, 0).await;
+        assert_balance(&ledger, 3, 35, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_rejects_internal_sub_accounts() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        // Crediting a Lock marker would freeze account 2
+        let lock = (2, AccountType::Lock).into();
+        assert!(matches!(
+            ledger
+                .transfer(
+                    "lock-2".to_string(),
+                    vec![(1.into(), 40.into())],
+                    vec![(lock, 40.into())],
+                )
+                .await,
+            Err(Error::InvalidAccount(account)) if account == lock
+        ));
+        assert!(!ledger.is_locked(2).await.expect("is_locked should succeed"));
+
+        let disputed = (1, AccountType::Disputed).into();
+        assert!(matches!(
+            ledger
+                .transfer(
+                    "hold-1".to_string(),
+                    vec![(1.into(), 40.into())],
+                    vec![(disputed, 40.into())],
+                )
+                .await,
+            Err(Error::InvalidAccount(account)) if account == disputed
+        ));
+        assert!(matches!(
+            ledger
+                .transfer(
+                    "release-1".to_string(),
+                    vec![(disputed, 40.into())],
+                    vec![(2.into(), 40.into())],
+                )
+                .await,
+            Err(Error::InvalidAccount(account)) if account == disputed
+        ));
+
+        assert_balance(&ledger, 1, 100, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_rejected_if_any_leg_is_short() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(2, "deposit-2".to_string(), 5.into())
+            .await
+            .expect("deposit should succeed");
+
+        let result = ledger
+            .transfer(
+                "purchase-1".to_string(),
+                vec![(1.into(), 50.into()), (2.into(), 10.into())],
+                vec![(3.into(), 60.into())],
+            )
+            .await;
+        assert!(matches!(result, Err(Error::NotEnough)));
+
+        // No leg was committed
+        assert_balance(&ledger, 1, 100, 0).await;
+        assert_balance(&ledger, 2, 5, 0).await;
+        assert_balance(&ledger, 3, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_rejects_imbalanced_and_invalid_legs() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        let result = ledger
+            .transfer(
+                "t-1".to_string(),
+                vec![(1.into(), 50.into())],
+                vec![(2.into(), 40.into())],
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Tx(transaction::Error::Imbalanced))
+        ));
+
+        let result = ledger
+            .transfer(
+                "t-2".to_string(),
+                vec![(1.into(), 50.into())],
+                vec![(2.into(), 60.into()), (3.into(), (-10).into())],
+            )
+            .await;
+        assert!(matches!(result, Err(Error::InvalidAmount)));
+
+        let result = ledger
+            .transfer("t-3".to_string(), vec![], vec![(2.into(), 10.into())])
+            .await;
+        assert!(matches!(result, Err(Error::InvalidAmount)));
+
+        assert_balance(&ledger, 1, 100, 0).await;
+        assert_balance(&ledger, 2, 0, 0).await;
+    }
+
+    async fn charged_back_account(ledger: &Ledger<Memory>, account: AccountId) {
+        ledger
+            .deposit(account, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(account, "deposit-2".to_string(), 30.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .dispute(account, "deposit-2".to_string())
+            .await
+            .expect("dispute should succeed");
+        ledger
+            .chargeback(account, "deposit-2".to_string())
+            .await
+            .expect("chargeback should succeed");
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_locks_account() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        assert!(
+            !ledger
+                .is_locked(account_id)
+                .await
+                .expect("is_locked should succeed")
+        );
+
+        charged_back_account(&ledger, account_id).await;
+
+        assert!(
+            ledger
+                .is_locked(account_id)
+                .await
+                .expect("is_locked should succeed")
+        );
+
+        let balances = ledger
+            .get_balances(account_id)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        assert!(balances.locked);
+        assert_eq!(*balances.chargeback, 30);
+        assert_balance(&ledger, account_id, 100, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_locked_account_rejects_withdrawals_and_transfers() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        charged_back_account(&ledger, account_id).await;
+        ledger
+            .deposit(2, "deposit-1".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+
+        let result = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 10.into())
+            .await;
+        assert!(matches!(result, Err(Error::Locked)));
+
+        // Locked as source
+        let result = ledger
+            .movement(account_id, 2, "move-1".to_string(), 10.into())
+            .await;
+        assert!(matches!(result, Err(Error::Locked)));
+
+        // Locked as destination
+        let result = ledger
+            .movement(2, account_id, "move-2".to_string(), 10.into())
+            .await;
+        assert!(matches!(result, Err(Error::Locked)));
+
+        assert_balance(&ledger, account_id, 100, 0).await;
+        assert_balance(&ledger, 2, 50, 0).await;
+
+        // Deposits are still accepted
+        ledger
+            .deposit(account_id, "deposit-3".to_string(), 5.into())
+            .await
+            .expect("deposit into a locked account should succeed");
+        assert_balance(&ledger, account_id, 105, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_unlock_restores_operations() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        charged_back_account(&ledger, account_id).await;
+
+        let unlock_id = ledger
+            .unlock(account_id, "ticket-1".to_string())
+            .await
+            .expect("unlock should succeed");
+        assert_ne!(unlock_id, [0u8; 32]);
+
+        let balances = ledger
+            .get_balances(account_id)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        assert!(!balances.locked);
+        // The chargeback record is kept
+        assert_eq!(*balances.chargeback, 30);
+
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 10.into())
+            .await
+            .expect("withdrawal after unlock should succeed");
+        assert_balance(&ledger, account_id, 90, 0).await;
+
+        // Nothing left to unlock
+        let result = ledger.unlock(account_id, "ticket-2".to_string()).await;
+        assert!(matches!(result, Err(Error::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_after_partial_spend_records_debt() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 70.into())
+            .await
+            .expect("withdrawal should succeed");
+
+        ledger
+            .dispute(account_id, "deposit-1".to_string())
+            .await
+            .expect("dispute of partially spent funds should succeed");
+
+        // The full deposit is held, the 70 already spent is owed
+        assert_balance(&ledger, account_id, -70, 100).await;
+
+        let result = ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 1.into())
+            .await;
+        assert!(matches!(result, Err(Error::NotEnough)));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_after_full_spend_records_debt() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("withdrawal should succeed");
+
+        ledger
+            .dispute(account_id, "deposit-1".to_string())
+            .await
+            .expect("dispute of fully spent funds should succeed");
+
+        assert_balance(&ledger, account_id, -100, 100).await;
+    }
+
+    #[tokio::test]
+    async fn test_deposits_pay_debt_down_first() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 70.into())
+            .await
+            .expect("withdrawal should succeed");
+        ledger
+            .dispute(account_id, "deposit-1".to_string())
+            .await
+            .expect("dispute should succeed");
+        assert_balance(&ledger, account_id, -70, 100).await;
+
+        // Smaller than the debt, all of it pays the debt down
+        ledger
+            .deposit(account_id, "deposit-2".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+        assert_balance(&ledger, account_id, -20, 100).await;
+        let result = ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 1.into())
+            .await;
+        assert!(matches!(result, Err(Error::NotEnough)));
+
+        // Larger than the debt, the remainder is spendable
+        ledger
+            .deposit(account_id, "deposit-3".to_string(), 45.into())
+            .await
+            .expect("deposit should succeed");
+        assert_balance(&ledger, account_id, 25, 100).await;
+
+        ledger
+            .withdraw(account_id, "withdraw-3".to_string(), 25.into())
+            .await
+            .expect("withdrawing the remainder should succeed");
+        assert_balance(&ledger, account_id, 0, 100).await;
+    }
+
+    #[tokio::test]
+    async fn test_deposit_paying_debt_can_be_disputed() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("withdrawal should succeed");
+        ledger
+            .dispute(account_id, "deposit-1".to_string())
+            .await
+            .expect("dispute should succeed");
+
+        // All of it pays the debt down, so it is only recorded in the Debt sub-account
+        ledger
+            .deposit(account_id, "deposit-2".to_string(), 60.into())
+            .await
+            .expect("deposit should succeed");
+        assert_balance(&ledger, account_id, -40, 100).await;
+
+        // The whole 60 is disputed and owed again
+        ledger
+            .dispute(account_id, "deposit-2".to_string())
+            .await
+            .expect("dispute should succeed");
+        assert_balance(&ledger, account_id, -100, 160).await;
+    }
+
+    #[tokio::test]
+    async fn test_resolve_after_shortfall_pays_debt_first() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 70.into())
+            .await
+            .expect("withdrawal should succeed");
+        ledger
+            .dispute(account_id, "deposit-1".to_string())
+            .await
+            .expect("dispute should succeed");
+
+        ledger
+            .resolve(account_id, "deposit-1".to_string())
+            .await
+            .expect("resolve should succeed");
+
+        // Back to the balance before the dispute, with the debt fully paid
+        assert_balance(&ledger, account_id, 30, 0).await;
+        ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 30.into())
+            .await
+            .expect("withdrawing the restored funds should succeed");
+        assert_balance(&ledger, account_id, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_debt_blocks_spending_incoming_movements() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 70.into())
+            .await
+            .expect("withdrawal should succeed");
+        ledger
+            .dispute(account_id, "deposit-1".to_string())
+            .await
+            .expect("dispute should succeed");
+
+        ledger
+            .deposit(2, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .movement(2, account_id, "move-1".to_string(), 80.into())
+            .await
+            .expect("movement should succeed");
+
+        // 80 in Main, 70 owed
+        assert_balance(&ledger, account_id, 10, 100).await;
+
+        let result = ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 11.into())
+            .await;
+        assert!(matches!(result, Err(Error::NotEnough)));
+        let result = ledger
+            .movement(account_id, 2, "move-2".to_string(), 11.into())
+            .await;
+        assert!(matches!(result, Err(Error::NotEnough)));
+
+        ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 10.into())
+            .await
+            .expect("withdrawing what is not owed should succeed");
+        assert_balance(&ledger, account_id, 0, 100).await;
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_replay_without_change_is_idempotent() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        for i in 0..2 {
+            ledger
+                .deposit(account_id, format!("deposit-{}", i), 50.into())
+                .await
+                .expect("deposit should succeed");
+        }
+
+        // Inputs match the amount exactly, so the withdrawal has no outputs
+        let tx_id = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 50.into())
+            .await
+            .expect("withdrawal should succeed");
+        assert_balance(&ledger, account_id, 50, 0).await;
+
+        let replayed = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 50.into())
+            .await
+            .expect("replayed withdrawal should succeed");
+        assert_eq!(replayed, tx_id);
+        assert_balance(&ledger, account_id, 50, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_replay_with_change_is_idempotent() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        let tx_id = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 30.into())
+            .await
+            .expect("withdrawal should succeed");
+        assert_balance(&ledger, account_id, 70, 0).await;
+
+        let replayed = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 30.into())
+            .await
+            .expect("replayed withdrawal should succeed");
+        assert_eq!(replayed, tx_id);
+        assert_balance(&ledger, account_id, 70, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_reference_clash_rejected() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 30.into())
+            .await
+            .expect("withdrawal should succeed");
+
+        // Same reference as a deposit
+        let result = ledger
+            .withdraw(account_id, "deposit-1".to_string(), 30.into())
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Storage(storage::Error::Duplicate))
+        ));
+
+        // Same reference as a withdrawal, but a different amount
+        let result = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 20.into())
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Storage(storage::Error::Duplicate))
+        ));
+
+        assert_balance(&ledger, account_id, 70, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_found_by_reference() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        let tx_id = ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("withdrawal should succeed");
+
+        let tx = ledger
+            .storage
+            .get_tx_by_reference(&account_id.into(), &"withdraw-1".into())
+            .await
+            .expect("get_tx_by_reference should succeed")
+            .expect("withdrawal should be indexed for the spender");
+        assert_eq!(tx.id(), tx_id);
+        assert_eq!(
+            tx.outputs(),
+            &[((account_id, AccountType::External).into(), 100.into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispute_withdrawal_credits_held_funds() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("withdrawal should succeed");
+        assert_balance(&ledger, account_id, 0, 0).await;
+
+        ledger
+            .dispute(account_id, "withdraw-1".to_string())
+            .await
+            .expect("dispute of a withdrawal should succeed");
+
+        // The withdrawn amount is provisionally credited, but not spendable
+        assert_balance(&ledger, account_id, 0, 100).await;
+        let result = ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 1.into())
+            .await;
+        assert!(matches!(result, Err(Error::NotEnough)));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_withdrawal_with_change() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 40.into())
+            .await
+            .expect("withdrawal should succeed");
+        assert_balance(&ledger, account_id, 60, 0).await;
+
+        ledger
+            .dispute(account_id, "withdraw-1".to_string())
+            .await
+            .expect("dispute of a withdrawal should succeed");
+
+        // Only the withdrawn amount is disputed, not the change
+        assert_balance(&ledger, account_id, 60, 40).await;
+    }
+
+    #[tokio::test]
+    async fn test_resolve_withdrawal_dispute_returns_funds() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 70.into())
+            .await
+            .expect("withdrawal should succeed");
+        ledger
+            .dispute(account_id, "withdraw-1".to_string())
+            .await
+            .expect("dispute should succeed");
+        assert_balance(&ledger, account_id, 30, 70).await;
+
+        ledger
+            .resolve(account_id, "withdraw-1".to_string())
+            .await
+            .expect("resolve should succeed");
+
+        // The payout never arrived, so the funds are back in Main
+        assert_balance(&ledger, account_id, 100, 0).await;
+        ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 100.into())
+            .await
+            .expect("withdrawing the returned funds should succeed");
+        assert_balance(&ledger, account_id, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_withdrawal_dispute_writes_off_funds() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 70.into())
+            .await
+            .expect("withdrawal should succeed");
+        ledger
+            .dispute(account_id, "withdraw-1".to_string())
+            .await
+            .expect("dispute should succeed");
+
+        ledger
+            .chargeback(account_id, "withdraw-1".to_string())
+            .await
+            .expect("chargeback should succeed");
+
+        // The provisional credit is gone and nothing is recorded as charged back
+        assert_balance(&ledger, account_id, 30, 0).await;
+        let balances = ledger
+            .get_balances(account_id)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        assert_eq!(*balances.chargeback, 0);
+        assert!(!balances.locked);
+
+        ledger
+            .withdraw(account_id, "withdraw-2".to_string(), 30.into())
+            .await
+            .expect("account should not be locked");
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_dispute_twice_rejected() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("withdrawal should succeed");
+        ledger
+            .dispute(account_id, "withdraw-1".to_string())
+            .await
+            .expect("dispute should succeed");
+
+        let result = ledger.dispute(account_id, "withdraw-1".to_string()).await;
+        assert!(matches!(
+            result,
+            Err(Error::Storage(storage::Error::Duplicate))
+        ));
+        assert_balance(&ledger, account_id, 0, 100).await;
+    }
+
+    #[tokio::test]
+    async fn test_dispute_withdrawal_of_other_account_not_found() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(1, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("withdrawal should succeed");
+
+        let result = ledger.dispute(2, "withdraw-1".to_string()).await;
+        assert!(matches!(result, Err(Error::NotFound)));
+        assert_balance(&ledger, 2, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_dispute_movement_fails_wrong_type() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .movement(1, 2, "move-1".to_string(), 100.into())
+            .await
+            .expect("movement should succeed");
+
+        for account_id in [1, 2] {
+            let result = ledger.dispute(account_id, "move-1".to_string()).await;
+            assert!(matches!(result, Err(Error::WrongType)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_reference_cannot_spoof_internal_reference() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "42".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        // Looks like the internal reference of a dispute over deposit 42
+        ledger
+            .deposit(account_id, "dispute:42".to_string(), 10.into())
+            .await
+            .expect("deposit with a prefixed reference should succeed");
+
+        ledger
+            .dispute(account_id, "42".to_string())
+            .await
+            .expect("dispute of deposit 42 should succeed");
+        assert_balance(&ledger, account_id, 10, 100).await;
+
+        // The client deposit is still a regular deposit that can be disputed on its own
+        ledger
+            .dispute(account_id, "dispute:42".to_string())
+            .await
+            .expect("dispute of the prefixed deposit should succeed");
+        assert_balance(&ledger, account_id, 0, 110).await;
+
+        ledger
+            .resolve(account_id, "42".to_string())
+            .await
+            .expect("resolve should succeed");
+        assert_balance(&ledger, account_id, 100, 10).await;
+    }
+
+    #[tokio::test]
+    async fn test_history_pages_and_stream() {
+        use futures::TryStreamExt;
+
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(account_id, "deposit-2".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(2, "deposit-3".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 30.into())
+            .await
+            .expect("withdraw should succeed");
+        ledger
+            .dispute(account_id, "deposit-2".to_string())
+            .await
+            .expect("dispute should succeed");
+
+        let everything = ledger
+            .get_history(account_id, None, HistoryQuery::default())
+            .await
+            .expect("get_history should succeed");
+        assert!(everything.next.is_none());
+
+        // The withdrawal was split first, so the change transaction is part of the history
+        let mut references = everything
+            .transactions
+            .iter()
+            .map(|tx| tx.reference())
+            .collect::<Vec<_>>();
+        references.sort();
+        let withdraw: Reference = "withdraw-1".into();
+        let mut expected = vec![
+            "deposit-1".into(),
+            "deposit-2".into(),
+            withdraw.clone(),
+            withdraw.to_kind(ReferenceKind::Change),
+            Reference::from("deposit-2").to_kind(ReferenceKind::Dispute),
+        ];
+        expected.sort();
+        assert_eq!(references, expected);
+
+        // Streaming with small pages yields the same transactions in the same order
+        let streamed = ledger
+            .history_stream(
+                account_id,
+                None,
+                HistoryQuery {
+                    limit: 2,
+                    ..Default::default()
+                },
+            )
+            .try_collect::<Vec<_>>()
+            .await
+            .expect("history_stream should succeed");
+        assert_eq!(
+            streamed.iter().map(Transaction::id).collect::<Vec<_>>(),
+            everything
+                .transactions
+                .iter()
+                .map(Transaction::id)
+                .collect::<Vec<_>>()
+        );
+
+        let first_page = ledger
+            .get_history(
+                account_id,
+                None,
+                HistoryQuery {
+                    limit: 2,
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("get_history should succeed");
+        assert_eq!(first_page.transactions.len(), 2);
+        assert!(first_page.next.is_some());
+
+        let disputed = ledger
+            .get_history(
+                account_id,
+                Some(AccountType::Disputed),
+                HistoryQuery::default(),
+            )
+            .await
+            .expect("get_history should succeed");
+        assert_eq!(disputed.transactions.len(), 1);
+        assert_eq!(
+            disputed.transactions[0].reference().kind(),
+            ReferenceKind::Dispute
+        );
+    }
+
+    #[tokio::test]
+    async fn test_balances_at_past_instants() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        // Keep every step at a distinct microsecond timestamp
+        let tick = || std::thread::sleep(std::time::Duration::from_millis(1));
+
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        tick();
+        ledger
+            .deposit(account_id, "deposit-2".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+        tick();
+        ledger
+            .dispute(account_id, "deposit-2".to_string())
+            .await
+            .expect("dispute should succeed");
+        tick();
+        ledger
+            .chargeback(account_id, "deposit-2".to_string())
+            .await
+            .expect("chargeback should succeed");
+
+        let history = ledger
+            .get_history(
+                account_id,
+                None,
+                HistoryQuery {
+                    order: Order::OldestFirst,
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("get_history should succeed");
+        let timestamps = history
+            .transactions
+            .iter()
+            .map(Transaction::timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps.len(), 4);
+
+        let before = ledger
+            .get_balances_at(account_id, timestamps[0] - 1)
+            .await
+            .expect("get_balances_at should succeed")[&DEFAULT_ASSET];
+        assert_eq!(*before.total, 0);
+
+        for (timestamp, available, disputed, chargeback, locked) in [
+            (timestamps[0], 100, 0, 0, false),
+            (timestamps[1], 150, 0, 0, false),
+            (timestamps[2] - 1, 150, 0, 0, false),
+            (timestamps[2], 100, 50, 0, false),
+            (timestamps[3], 100, 0, 50, true),
+        ] {
+            let balances = ledger
+                .get_balances_at(account_id, timestamp)
+                .await
+                .expect("get_balances_at should succeed")[&DEFAULT_ASSET];
+            assert_eq!(*balances.available, available);
+            assert_eq!(*balances.disputed, disputed);
+            assert_eq!(*balances.chargeback, chargeback);
+            assert_eq!(balances.locked, locked);
+        }
+
+        // The latest instant matches the current balances
+        let now = ledger
+            .get_balances(account_id)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        let at = ledger
+            .get_balances_at(account_id, u64::MAX)
+            .await
+            .expect("get_balances_at should succeed")[&DEFAULT_ASSET];
+        assert_eq!(*now.total, *at.total);
+        assert_eq!(now.locked, at.locked);
+    }
+
+    #[tokio::test]
+    async fn test_trial_balance_sums_to_zero() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(1, "deposit-2".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(2, "deposit-1".to_string(), 80.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(1, "withdraw-1".to_string(), 30.into())
+            .await
+            .expect("withdraw should succeed");
+        ledger
+            .movement(1, 2, "move-1".to_string(), 90.into())
+            .await
+            .expect("movement should succeed");
+
+        // Disputing a spent deposit creates debt, disputing a withdrawal pulls funds back
+        ledger
+            .dispute(1, "deposit-1".to_string())
+            .await
+            .expect("dispute should succeed");
+        ledger
+            .chargeback(1, "deposit-1".to_string())
+            .await
+            .expect("chargeback should succeed");
+        ledger
+            .dispute(1, "withdraw-1".to_string())
+            .await
+            .expect("dispute should succeed");
+        ledger
+            .chargeback(1, "withdraw-1".to_string())
+            .await
+            .expect("chargeback should succeed");
+
+        let trial_balance = ledger
+            .trial_balance()
+            .await
+            .expect("trial balance should succeed");
+        assert_eq!(*trial_balance.totals[&DEFAULT_ASSET], 0);
+
+        let balance_of = |account: FullAccount| {
+            trial_balance
+                .accounts
+                .iter()
+                .find(|(candidate, _)| *candidate == account)
+                .map(|(_, amount)| **amount)
+        };
+
+        // Money that entered minus money that left
+        assert_eq!(balance_of((1, AccountType::External).into()), Some(-120));
+        assert_eq!(balance_of((2, AccountType::External).into()), Some(-80));
+        assert_eq!(balance_of((1, AccountType::Chargeback).into()), Some(100));
+    }
+
+    #[tokio::test]
+    async fn test_verify_balances_after_every_operation() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(2, "deposit-1".to_string(), 40.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(1, "withdraw-1".to_string(), 30.into())
+            .await
+            .expect("withdraw should succeed");
+        ledger
+            .movement(1, 2, "move-1".to_string(), 50.into())
+            .await
+            .expect("movement should succeed");
+        ledger
+            .dispute(1, "deposit-1".to_string())
+            .await
+            .expect("dispute should succeed");
+        ledger
+            .chargeback(1, "deposit-1".to_string())
+            .await
+            .expect("chargeback should succeed");
+
+        let drifts = ledger
+            .verify_balances()
+            .await
+            .expect("verify_balances should succeed");
+        assert!(drifts.is_empty(), "unexpected drift: {drifts:?}");
+
+        // Balances are read from the cache and match the UTXOs
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, -80);
+        assert_eq!(*balances.chargeback, 100);
+        assert!(balances.locked);
+        let balances = ledger.get_balances(2).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 90);
+    }
+
+    #[tokio::test]
+    async fn test_trial_balance_detects_money_from_nothing() {
+        let ledger = Ledger::default();
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        // A mint without a counterparty, as stored before External sub-accounts existed
+        let tx = Transaction::new(vec![], vec![(1.into(), 25.into())], "legacy".into(), None)
+            .expect("mint should be valid");
+        ledger
+            .storage
+            .store_tx(tx)
+            .await
+            .expect("store_tx should succeed");
+
+        let result = ledger.trial_balance().await;
+        assert!(matches!(result, Err(Error::Unbalanced(DEFAULT_ASSET, amount)) if *amount == 25));
+    }
+
+    #[tokio::test]
+    async fn test_failed_withdrawal_leaves_no_exchange() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        // Build up a debt, so a later deposit is fully absorbed by it
+        ledger
+            .deposit(account_id, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 100.into())
+            .await
+            .expect("withdraw should succeed");
+        ledger
+            .dispute(account_id, "deposit-1".to_string())
+            .await
+            .expect("dispute should succeed");
+
+        // Only the Debt and External sub-accounts are indexed under this reference
+        ledger
+            .deposit(account_id, "shared".to_string(), 30.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(account_id, "deposit-2".to_string(), 150.into())
+            .await
+            .expect("deposit should succeed");
+        assert_balance(&ledger, account_id, 80, 100).await;
+
+        // The exchange is valid but the withdrawal collides on the External sub-account
+        let result = ledger
+            .withdraw(account_id, "shared".to_string(), 50.into())
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Storage(storage::Error::Duplicate))
+        ));
+
+        // Nothing was committed, the change split included
+        assert_balance(&ledger, account_id, 80, 100).await;
+        let unspent = ledger
+            .storage
+            .get_unspent(&account_id.into(), None)
+            .await
+            .expect("get_unspent should succeed");
+        assert_eq!(unspent.len(), 1);
+        let exchange = ledger
+            .storage
+            .get_tx_by_reference(
+                &account_id.into(),
+                &Reference::from("shared").to_kind(ReferenceKind::Change),
+            )
+            .await
+            .expect("get_tx_by_reference should succeed");
+        assert!(exchange.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_coin_selection_strategies() {
+        async fn ledger_with(coin_selection: CoinSelection) -> Ledger<Memory> {
+            let mut ledger = Ledger::default();
+            ledger.set_coin_selection(coin_selection);
+            for (i, amount) in [50, 30, 20].into_iter().enumerate() {
+                ledger
+                    .deposit(1, format!("deposit-{i}"), amount.into())
+                    .await
+                    .expect("deposit should succeed");
+            }
+            ledger
+        }
+
+        async fn unspent(ledger: &Ledger<Memory>) -> Vec<i128> {
+            let mut amounts = ledger
+                .storage
+                .get_unspent(&1.into(), None)
+                .await
+                .expect("get_unspent should succeed")
+                .iter()
+                .map(|utxo| *utxo.amount())
+                .collect::<Vec<_>>();
+            amounts.sort();
+            amounts
+        }
+
+        // The oldest deposit is split
+        let ledger = ledger_with(CoinSelection::OldestFirst).await;
+        ledger
+            .withdraw(1, "withdraw-1".to_string(), 20.into())
+            .await
+            .expect("withdraw should succeed");
+        assert_eq!(unspent(&ledger).await, vec![20, 30, 30]);
+
+        // The largest deposits are combined
+        let ledger = ledger_with(CoinSelection::LargestFirst).await;
+        ledger
+            .withdraw(1, "withdraw-1".to_string(), 60.into())
+            .await
+            .expect("withdraw should succeed");
+        assert_eq!(unspent(&ledger).await, vec![20, 20]);
+
+        // A single deposit covers it without change
+        let ledger = ledger_with(CoinSelection::SmallestSufficient).await;
+        ledger
+            .withdraw(1, "withdraw-1".to_string(), 20.into())
+            .await
+            .expect("withdraw should succeed");
+        assert_eq!(unspent(&ledger).await, vec![30, 50]);
+
+        // Two deposits add up to the amount, no change and no exchange transaction
+        let ledger = ledger_with(CoinSelection::ExactMatch).await;
+        ledger
+            .withdraw(1, "withdraw-1".to_string(), 70.into())
+            .await
+            .expect("withdraw should succeed");
+        assert_eq!(unspent(&ledger).await, vec![30]);
+        let exchange = ledger
+            .storage
+            .get_tx_by_reference(
+                &1.into(),
+                &Reference::from("withdraw-1").to_kind(ReferenceKind::Change),
+            )
+            .await
+            .expect("get_tx_by_reference should succeed");
+        assert!(exchange.is_none());
+        assert_balance(&ledger, 1, 30, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_consolidate_merges_smallest_utxos() {
+        let ledger = Ledger::default();
+        let account_id: AccountId = 1;
+
+        for (i, amount) in [40, 10, 30, 20, 50].into_iter().enumerate() {
+            ledger
+                .deposit(account_id, format!("deposit-{i}"), amount.into())
+                .await
+                .expect("deposit should succeed");
+        }
+
+        let tx_id = ledger
+            .consolidate(account_id, AccountType::Main, 3)
+            .await
+            .expect("consolidate should succeed")
+            .expect("there are UTXOs to merge");
+        assert_balance(&ledger, account_id, 150, 0).await;
+
+        let mut amounts = ledger
+            .storage
+            .get_unspent(&account_id.into(), None)
+            .await
+            .expect("get_unspent should succeed")
+            .iter()
+            .map(|utxo| *utxo.amount())
+            .collect::<Vec<_>>();
+        amounts.sort();
+        assert_eq!(amounts, vec![40, 50, 60]);
+
+        // Marked as a system movement in the history
+        let history = ledger
+            .get_history(account_id, None, HistoryQuery::default())
+            .await
+            .expect("get_history should succeed");
+        let consolidation = history
+            .transactions
+            .iter()
+            .find(|tx| tx.id() == tx_id)
+            .expect("consolidation should be in the history");
+        assert_eq!(consolidation.reference().kind(), ReferenceKind::System);
+        assert_eq!(consolidation.inputs().len(), 3);
+
+        // Merging everything leaves a single UTXO, after that there is nothing to do
+        ledger
+            .consolidate(account_id, AccountType::Main, 100)
+            .await
+            .expect("consolidate should succeed")
+            .expect("there are UTXOs to merge");
+        let result = ledger
+            .consolidate(account_id, AccountType::Main, 100)
+            .await
+            .expect("consolidate should succeed");
+        assert!(result.is_none());
+        assert_balance(&ledger, account_id, 150, 0).await;
+
+        ledger
+            .withdraw(account_id, "withdraw-1".to_string(), 150.into())
+            .await
+            .expect("withdraw should succeed");
+        assert_balance(&ledger, account_id, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_consolidate_all_walks_every_sub_account() {
+        let ledger = Ledger::default();
+
+        for i in 0..4 {
+            ledger
+                .deposit(1, format!("deposit-{i}"), 10.into())
+                .await
+                .expect("deposit should succeed");
+            ledger
+                .deposit(2, format!("deposit-{i}"), 5.into())
+                .await
+                .expect("deposit should succeed");
+        }
+        ledger
+            .deposit(3, "deposit-0".to_string(), 5.into())
+            .await
+            .expect("deposit should succeed");
+
+        // Account 1 and 2 Main sub-accounts, the negative External UTXOs are left alone
+        let consolidated = ledger
+            .consolidate_all(10)
+            .await
+            .expect("consolidate_all should succeed");
+        assert_eq!(consolidated.len(), 2);
+
+        for (account, total) in [(1, 40), (2, 20), (3, 5)] {
+            let unspent = ledger
+                .storage
+                .get_unspent(&account.into(), None)
+                .await
+                .expect("get_unspent should succeed");
+            assert_eq!(unspent.len(), 1);
+            assert_balance(&ledger, account, total, 0).await;
+        }
+
+        ledger
+            .trial_balance()
+            .await
+            .expect("the ledger should balance");
+    }
+
+    /// Storage that reports every spend as already spent, as if another task always won.
+    #[derive(Default)]
+    struct AlwaysContended {
+        inner: Memory,
+        attempts: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for AlwaysContended {
+        async fn get_unspent(
+            &self,
+            account: &FullAccount,
+            target_amount: Option<Amount>,
+        ) -> Result<Vec<Utxo>, storage::Error> {
+            self.inner.get_unspent(account, target_amount).await
+        }
+
+        async fn get_unspent_sorted(
+            &self,
+            account: &FullAccount,
+            order: UtxoOrder,
+            target_amount: Option<Amount>,
+        ) -> Result<Vec<Utxo>, storage::Error> {
+            self.inner
+                .get_unspent_sorted(account, order, target_amount)
+                .await
+        }
+
+        async fn get_unspent_at(
+            &self,
+            account: &FullAccount,
+            timestamp: u64,
+        ) -> Result<Vec<Utxo>, storage::Error> {
+            self.inner.get_unspent_at(account, timestamp).await
+        }
+
+        async fn get_balance(&self, account: &FullAccount) -> Result<Amount, storage::Error> {
+            self.inner.get_balance(account).await
+        }
+
+        async fn get_credit_limit(&self, account: &FullAccount) -> Result<Amount, storage::Error> {
+            self.inner.get_credit_limit(account).await
+        }
+
+        async fn set_credit_limit(
+            &self,
+            account: &FullAccount,
+            limit: Amount,
+        ) -> Result<(), storage::Error> {
+            self.inner.set_credit_limit(account, limit).await
+        }
+
+        async fn snapshot<T, F>(&self, reads: F) -> Result<T, storage::Error>
+        where
+            T: Send + 'static,
+            F: FnOnce(&dyn storage::Snapshot) -> Result<T, storage::Error> + Send + 'static,
+        {
+            self.inner.snapshot(reads).await
+        }
+
+        async fn get_tx(&self, tx_id: &HashId) -> Result<Option<Transaction>, storage::Error> {
+            self.inner.get_tx(tx_id).await
+        }
+
+        async fn get_tx_by_reference(
+            &self,
+            account: &FullAccount,
+            reference: &Reference,
+        ) -> Result<Option<Transaction>, storage::Error> {
+            self.inner.get_tx_by_reference(account, reference).await
+        }
+
+        async fn get_history(
+            &self,
+            account: AccountId,
+            sub_account: Option<AccountType>,
+            query: &HistoryQuery,
+        ) -> Result<Vec<Transaction>, storage::Error> {
+            self.inner.get_history(account, sub_account, query).await
+        }
+
+        async fn get_accounts(
+            &self,
+        ) -> impl Stream<Item = Result<FullAccount, storage::Error>> + Send + Sync + 'static + Unpin
+        {
+            self.inner.get_accounts().await
+        }
+
+        async fn store_tx(&self, tx: Transaction) -> Result<(), storage::Error> {
+            self.store_txs(vec![tx]).await
+        }
+
+        async fn store_txs(&self, txs: Vec<Transaction>) -> Result<(), storage::Error> {
+            if let Some(input) = txs.iter().find_map(|tx| tx.inputs().first()) {
+                self.attempts
+                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                return Err(storage::Error::SpentUtxo(input.id()));
+            }
+            self.inner.store_txs(txs).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_contention_error_after_retries_exhausted() {
+        let ledger = Ledger::with_retry_policy(
+            AlwaysContended::default(),
+            RetryPolicy {
+                max_attempts: 3,
+                ..Default::default()
+            },
+        );
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        let result = ledger
+            .withdraw(1, "withdraw-1".to_string(), 40.into())
+            .await;
+        assert!(matches!(result, Err(Error::Contention)));
+        assert_eq!(
+            ledger
+                .storage
+                .attempts
+                .load(std::sync::atomic::Ordering::SeqCst),
+            3
+        );
+
+        let result = ledger.movement(1, 2, "move-1".to_string(), 40.into()).await;
+        assert!(matches!(result, Err(Error::Contention)));
+        assert_eq!(
+            ledger
+                .storage
+                .attempts
+                .load(std::sync::atomic::Ordering::SeqCst),
+            6
+        );
+    }
+
+    /// Many tasks spend from the same account at once, all of them picking the same UTXOs.
+    async fn stress_concurrent_spends<S>(storage: S)
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        const TASKS: u16 = 64;
+
+        let ledger = Arc::new(Ledger::with_retry_policy(
+            storage,
+            RetryPolicy {
+                max_attempts: 100,
+                ..Default::default()
+            },
+        ));
+
+        for i in 0..TASKS {
+            ledger
+                .deposit(1, format!("deposit-{i}"), 10.into())
+                .await
+                .expect("deposit should succeed");
+        }
+
+        let tasks = (0..TASKS)
+            .map(|i| {
+                let ledger = ledger.clone();
+                tokio::spawn(async move {
+                    if i % 2 == 0 {
+                        ledger.withdraw(1, format!("withdraw-{i}"), 10.into()).await
+                    } else {
+                        ledger.movement(1, 2, format!("move-{i}"), 10.into()).await
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await
+                .expect("task should not panic")
+                .expect("every operation should eventually succeed");
+        }
+
+        let balances = ledger
+            .get_balances(1)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        assert_eq!(*balances.total, 0);
+        let balances = ledger
+            .get_balances(2)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        assert_eq!(*balances.total, 10 * i128::from(TASKS / 2));
+        ledger
+            .trial_balance()
+            .await
+            .expect("the ledger should balance");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_stress_concurrent_spends_memory() {
+        stress_concurrent_spends(Memory::default()).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_stress_concurrent_spends_sqlite() {
+        stress_concurrent_spends(storage::Sqlite::default()).await;
+    }
+
+    /// Balances are read while disputes move funds between Main and Disputed, the funds must
+    /// never show up in both or in neither.
+    async fn consistent_balance_reads<S>(storage: S)
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        const DEPOSITS: u16 = 32;
+        const READERS: usize = 4;
+
+        let ledger = Arc::new(Ledger::new(storage));
+        for i in 0..DEPOSITS {
+            ledger
+                .deposit(1, format!("deposit-{i}"), 10.into())
+                .await
+                .expect("deposit should succeed");
+        }
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers = (0..READERS)
+            .map(|_| {
+                let ledger = ledger.clone();
+                let done = done.clone();
+                tokio::spawn(async move {
+                    while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                        let balances = ledger
+                            .get_balances(1)
+                            .await
+                            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+                        assert_eq!(*balances.total, 10 * i128::from(DEPOSITS));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 0..DEPOSITS {
+            ledger
+                .dispute(1, format!("deposit-{i}"))
+                .await
+                .expect("dispute should succeed");
+            ledger
+                .resolve(1, format!("deposit-{i}"))
+                .await
+                .expect("resolve should succeed");
+        }
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+
+        for reader in readers {
+            reader.await.expect("balances should always add up");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_consistent_balance_reads_memory() {
+        consistent_balance_reads(Memory::default()).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_consistent_balance_reads_sqlite() {
+        consistent_balance_reads(storage::Sqlite::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_deposit_rejects_non_positive_amounts() {
+        let ledger = Ledger::new(Memory::default());
+
+        for (reference, amount) in [("deposit-zero", 0), ("deposit-negative", -5)] {
+            assert!(matches!(
+                ledger.deposit(1, reference, amount.into()).await,
+                Err(Error::InvalidAmount)
+            ));
+        }
+
+        assert_balance(&ledger, 1, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_closed_dispute_cannot_be_closed_again() {
+        let ledger = Ledger::new(Memory::default());
+        for reference in ["deposit-1", "deposit-2"] {
+            ledger
+                .deposit(1, reference, 100.into())
+                .await
+                .expect("deposit should succeed");
+            ledger
+                .dispute(1, reference)
+                .await
+                .expect("dispute should succeed");
+        }
+
+        // Resolve after a chargeback, deposit-2 is still disputed and must stay held
+        ledger
+            .chargeback(1, "deposit-1")
+            .await
+            .expect("chargeback should succeed");
+        assert!(matches!(
+            ledger.resolve(1, "deposit-1").await,
+            Err(Error::DisputeClosed)
+        ));
+        assert_balance(&ledger, 1, 0, 100).await;
+
+        // Chargeback after a resolve
+        ledger
+            .resolve(1, "deposit-2")
+            .await
+            .expect("resolve should succeed");
+        assert!(matches!(
+            ledger.chargeback(1, "deposit-2").await,
+            Err(Error::DisputeClosed)
+        ));
+        assert_balance(&ledger, 1, 100, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_rejects_non_positive_amounts() {
+        let ledger = Ledger::new(Memory::default());
+        ledger
+            .deposit(1, "deposit-1", 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        for (reference, amount) in [("withdraw-zero", 0), ("withdraw-negative", -5)] {
+            assert!(matches!(
+                ledger.withdraw(1, reference, amount.into()).await,
+                Err(Error::InvalidAmount)
+            ));
+        }
+
+        assert_balance(&ledger, 1, 100, 0).await;
+    }
+
+    /// Deposits race to repay the same debt, each reading it before the others are stored.
+    async fn concurrent_repayments<S>(storage: S)
+    where
+        S: Storage + Send + Sync + 'static,
+    {
+        const DEPOSITS: u16 = 8;
+
+        let ledger = Arc::new(Ledger::new(storage));
+        ledger
+            .deposit(1, "deposit-1", 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .withdraw(1, "withdraw-1", 100.into())
+            .await
+            .expect("withdraw should succeed");
+        ledger
+            .dispute(1, "deposit-1")
+            .await
+            .expect("dispute should succeed");
+
+        let tasks = (0..DEPOSITS)
+            .map(|i| {
+                let ledger = ledger.clone();
+                tokio::spawn(async move {
+                    ledger
+                        .deposit(1, format!("repay-{i}"), 30.into())
+                        .await
+                        .expect("deposit should succeed");
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.expect("task should not panic");
+        }
+
+        let balances = ledger
+            .get_balances(1)
+            .await
+            .expect("get_balances should succeed")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 30 * i128::from(DEPOSITS) - 100);
+        // The debt is repaid exactly once, never overpaid
+        let debt = ledger
+            .storage
+            .get_balance(&(1, AccountType::Debt).into())
+            .await
+            .expect("get_balance should succeed");
+        assert_eq!(*debt, 0);
+        ledger
+            .trial_balance()
+            .await
+            .expect("the ledger should balance");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_repayments_memory() {
+        concurrent_repayments(Memory::default()).await;
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_repayments_sqlite() {
+        concurrent_repayments(storage::Sqlite::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_movement_uses_the_ledger_asset() {
+        let ledger = Ledger::new(Memory::default()).for_asset(7);
+        ledger
+            .deposit(1, "deposit-1", 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        ledger
+            .movement(1, 2, "m1", 10.into())
+            .await
+            .expect("movement should succeed");
+
+        for (account, expected) in [(1, 90), (2, 10)] {
+            let balances = ledger
+                .get_balances(account)
+                .await
+                .expect("get_balances should succeed");
+            assert_eq!(*balances[&7].available, expected);
+            // Nothing moved in the default asset
+            assert!(!balances.contains_key(&DEFAULT_ASSET));
+        }
+    }
+
+    #[test]
+    fn test_transaction_sums_do_not_overflow() {
+        let inputs = vec![
+            Utxo::new(([0u8; 32], 0).into(), i128::MAX.into(), DEFAULT_ASSET),
+            Utxo::new(([0u8; 32], 1).into(), 1.into(), DEFAULT_ASSET),
+        ];
+        let result = Transaction::new(inputs, vec![(1.into(), 1.into())], "overflow".into(), None);
+        assert!(matches!(result, Err(transaction::Error::Math)));
+
+        let input = Utxo::new(([0u8; 32], 0).into(), 1.into(), DEFAULT_ASSET);
+        let result = Transaction::new(
+            vec![input],
+            vec![(1.into(), i128::MAX.into()), (2.into(), 1.into())],
+            "overflow".into(),
+            None,
+        );
+        assert!(matches!(result, Err(transaction::Error::Math)));
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_draws_on_credit_line() {
+        let ledger = Ledger::default();
+
+        assert!(matches!(
+            ledger.set_credit_limit(1, (-1).into()).await,
+            Err(Error::InvalidAmount)
+        ));
+        ledger
+            .set_credit_limit(1, 100.into())
+            .await
+            .expect("set_credit_limit should succeed");
+        ledger
+            .deposit(1, "deposit-1".to_string(), 50.into())
+            .await
+            .expect("deposit should succeed");
+
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.credit_used, 0);
+        assert_eq!(*balances.credit_remaining, 100);
+
+        // 50 of own funds and 70 of credit
+        let withdrawal = ledger
+            .withdraw(1, "withdraw-1".to_string(), 120.into())
+            .await
+            .expect("withdraw within the credit line should succeed");
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 0);
+        assert_eq!(*balances.credit_used, 70);
+        assert_eq!(*balances.credit_remaining, 30);
+
+        // Replays are still recognised
+        assert_eq!(
+            ledger
+                .withdraw(1, "withdraw-1".to_string(), 120.into())
+                .await
+                .expect("replay should succeed"),
+            withdrawal
+        );
+
+        assert!(matches!(
+            ledger
+                .withdraw(1, "withdraw-2".to_string(), 40.into())
+                .await,
+            Err(Error::NotEnough)
+        ));
+
+        // Deposits repay the credit before landing in Main
+        ledger
+            .deposit(1, "deposit-2".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 30);
+        assert_eq!(*balances.credit_used, 0);
+        assert_eq!(*balances.credit_remaining, 100);
+
+        ledger
+            .trial_balance()
+            .await
+            .expect("the ledger should balance");
+        assert!(ledger.verify_balances().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_credit_line_cannot_be_overdrawn_concurrently() {
+        let ledger = Arc::new(Ledger::default());
+        ledger
+            .set_credit_limit(1, 100.into())
+            .await
+            .expect("set_credit_limit should succeed");
+
+        let tasks = (0..8)
+            .map(|i| {
+                let ledger = ledger.clone();
+                tokio::spawn(
+                    async move { ledger.withdraw(1, format!("withdraw-{i}"), 30.into()).await },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut succeeded = 0;
+        for task in tasks {
+            match task.await.expect("task should not panic") {
+                Ok(_) => succeeded += 1,
+                Err(Error::NotEnough) => {}
+                Err(err) => panic!("unexpected error {err:?}"),
+            }
+        }
+
+        assert_eq!(succeeded, 3);
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.credit_used, 90);
+        assert_eq!(*balances.credit_remaining, 10);
+    }
+
+    #[tokio::test]
+    async fn test_authorize_and_partial_capture() {
+        let ledger = Ledger::default();
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        assert!(matches!(
+            ledger
+                .authorize(1, "auth-1".to_string(), 0.into(), None)
+                .await,
+            Err(Error::InvalidAmount)
+        ));
+        assert!(matches!(
+            ledger
+                .authorize(1, "auth-1".to_string(), 150.into(), None)
+                .await,
+            Err(Error::NotEnough)
+        ));
+
+        ledger
+            .authorize(1, "auth-1".to_string(), 60.into(), None)
+            .await
+            .expect("authorize should succeed");
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 40);
+        assert_eq!(*balances.pending, 60);
+        assert_eq!(*balances.total, 100);
+
+        // Held funds cannot be spent
+        assert!(matches!(
+            ledger
+                .withdraw(1, "withdraw-1".to_string(), 50.into())
+                .await,
+            Err(Error::NotEnough)
+        ));
+
+        assert!(matches!(
+            ledger.capture(1, "auth-2".to_string(), 10.into()).await,
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            ledger.capture(1, "auth-1".to_string(), 61.into()).await,
+            Err(Error::InvalidAmount)
+        ));
+
+        // 45 leave the ledger, the other 15 go back to Main
+        ledger
+            .capture(1, "auth-1".to_string(), 45.into())
+            .await
+            .expect("capture should succeed");
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 55);
+        assert_eq!(*balances.pending, 0);
+        assert_eq!(*balances.total, 55);
+
+        assert!(matches!(
+            ledger.capture(1, "auth-1".to_string(), 10.into()).await,
+            Err(Error::HoldClosed)
+        ));
+        assert!(matches!(
+            ledger.void(1, "auth-1".to_string()).await,
+            Err(Error::HoldClosed)
+        ));
+
+        ledger
+            .trial_balance()
+            .await
+            .expect("the ledger should balance");
+    }
+
+    #[tokio::test]
+    async fn test_void_releases_hold() {
+        let ledger = Ledger::default();
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .authorize(1, "auth-1".to_string(), 100.into(), None)
+            .await
+            .expect("authorize should succeed");
+
+        ledger
+            .void(1, "auth-1".to_string())
+            .await
+            .expect("void should succeed");
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 100);
+        assert_eq!(*balances.pending, 0);
+
+        assert!(matches!(
+            ledger.capture(1, "auth-1".to_string(), 100.into()).await,
+            Err(Error::HoldClosed)
+        ));
+        // The reference stays taken
+        assert!(matches!(
+            ledger
+                .authorize(1, "auth-1".to_string(), 10.into(), None)
+                .await,
+            Err(Error::Storage(storage::Error::Duplicate))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expired_holds_are_released() {
+        let ledger = Ledger::default();
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(2, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+
+        // Already expired, and far in the future
+        ledger
+            .authorize(1, "auth-1".to_string(), 30.into(), Some(1))
+            .await
+            .expect("authorize should succeed");
+        ledger
+            .authorize(1, "auth-2".to_string(), 20.into(), Some(u64::MAX))
+            .await
+            .expect("authorize should succeed");
+        ledger
+            .authorize(2, "auth-1".to_string(), 50.into(), Some(1))
+            .await
+            .expect("authorize should succeed");
+
+        assert!(matches!(
+            ledger.capture(1, "auth-1".to_string(), 30.into()).await,
+            Err(Error::HoldExpired)
+        ));
+
+        let released = ledger
+            .release_expired()
+            .await
+            .expect("release_expired should succeed");
+        assert_eq!(released.len(), 2);
+
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 80);
+        assert_eq!(*balances.pending, 20);
+        let balances = ledger.get_balances(2).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 100);
+        assert_eq!(*balances.pending, 0);
+
+        // Nothing left to release, and the live hold can be captured
+        assert!(ledger.release_expired().await.unwrap().is_empty());
+        ledger
+            .capture(1, "auth-2".to_string(), 20.into())
+            .await
+            .expect("capture should succeed");
+    }
+
+    #[tokio::test]
+    async fn test_locked_account_rejects_captures() {
+        let ledger = Ledger::default();
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .deposit(1, "deposit-2".to_string(), 30.into())
+            .await
+            .expect("deposit should succeed");
+        ledger
+            .authorize(1, "auth-1".to_string(), 60.into(), None)
+            .await
+            .expect("authorize should succeed");
+        ledger
+            .dispute(1, "deposit-2".to_string())
+            .await
+            .expect("dispute should succeed");
+        ledger
+            .chargeback(1, "deposit-2".to_string())
+            .await
+            .expect("chargeback should succeed");
+
+        assert!(matches!(
+            ledger.capture(1, "auth-1".to_string(), 60.into()).await,
+            Err(Error::Locked)
+        ));
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.pending, 60);
+
+        // Voiding only returns the funds to the account, so it is still allowed
+        ledger
+            .void(1, "auth-1".to_string())
+            .await
+            .expect("void should succeed");
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!(*balances.available, 100);
+        assert_eq!(*balances.pending, 0);
+    }
+
+    #[tokio::test]
+    async fn test_assets_are_kept_apart() {
+        const EUR: AssetId = 1;
+        let ledger = Ledger::default();
+        let eur = ledger.for_asset(EUR);
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        // References are per asset
+        eur.deposit(1, "deposit-1".to_string(), 30.into())
+            .await
+            .expect("deposit should succeed");
+
+        // The other asset's funds cannot be spent
+        assert!(matches!(
+            eur.withdraw(1, "withdraw-1".to_string(), 50.into()).await,
+            Err(Error::NotEnough)
+        ));
+        eur.withdraw(1, "withdraw-1".to_string(), 20.into())
+            .await
+            .expect("withdraw should succeed");
+
+        let balances = ledger.get_balances(1).await.expect("get_balances");
+        assert_eq!(balances.len(), 2);
+        assert_eq!(*balances[&DEFAULT_ASSET].available, 100);
+        assert_eq!(*balances[&EUR].available, 10);
+
+        // The ledger's own asset is always listed
+        let balances = eur.get_balances(2).await.expect("get_balances");
+        assert_eq!(balances.keys().copied().collect::<Vec<_>>(), vec![EUR]);
+        assert_eq!(*balances[&EUR].total, 0);
+
+        // An exchange between two accounts balances per asset
+        assert!(matches!(
+            ledger
+                .transfer(
+                    "swap-1".to_string(),
+                    vec![(1.into(), 50.into())],
+                    vec![((2, AccountType::Main, EUR).into(), 50.into())],
+                )
+                .await,
+            Err(Error::Tx(transaction::Error::Imbalanced))
+        ));
+        ledger
+            .transfer(
+                "swap-1".to_string(),
+                vec![
+                    (1.into(), 50.into()),
+                    ((1, AccountType::Main, EUR).into(), 10.into()),
+                ],
+                vec![
+                    (2.into(), 50.into()),
+                    ((2, AccountType::Main, EUR).into(), 10.into()),
+                ],
+            )
+            .await
+            .expect("transfer should succeed");
+
+        let balances = ledger.get_balances(2).await.expect("get_balances");
+        assert_eq!(*balances[&DEFAULT_ASSET].available, 50);
+        assert_eq!(*balances[&EUR].available, 10);
+
+        let trial_balance = ledger.trial_balance().await.expect("trial_balance");
+        assert_eq!(*trial_balance.totals[&DEFAULT_ASSET], 0);
+        assert_eq!(*trial_balance.totals[&EUR], 0);
+    }
+
+    #[test]
+    fn test_transaction_balances_per_asset() {
+        let input = Utxo::new(([0u8; 32], 0).into(), 100.into(), DEFAULT_ASSET);
+
+        // Same total, but value moves from one asset to another
+        let result = Transaction::new(
+            vec![input],
+            vec![
+                (1.into(), 50.into()),
+                ((1, AccountType::Main, 1).into(), 50.into()),
+            ],
+            "mix".into(),
+            None,
+        );
+        assert!(matches!(result, Err(transaction::Error::Imbalanced)));
+
+        // A zero-value output of another asset, like a lock marker, is fine
+        Transaction::new(
+            vec![input],
+            vec![
+                (1.into(), 100.into()),
+                ((1, AccountType::Lock, 1).into(), 0.into()),
+            ],
+            "marker".into(),
+            None,
+        )
+        .expect("transaction should be valid");
+    }
+
+    #[tokio::test]
+    async fn test_exchange_between_assets() {
+        const EUR: AssetId = 1;
+        const HOUSE: AccountId = 99;
+        let usd = Ledger::default();
+        let eur = usd.for_asset(EUR);
+        // 0.9215 EUR per USD, both with 4 decimals
+        let rate = Rate {
+            base: 10_000.into(),
+            quote: 9_215.into(),
+        };
+
+        usd.deposit(1, "deposit-1".to_string(), 1_000_000.into())
+            .await
+            .expect("deposit should succeed");
+        eur.deposit(HOUSE, "liquidity".to_string(), 1_000_000.into())
+            .await
+            .expect("deposit should succeed");
+
+        // 0.0001 USD is worth 0.00009215 EUR, which cannot be paid out exactly
+        assert!(matches!(
+            usd.exchange(1, HOUSE, "fx-1".to_string(), 1.into(), EUR, rate)
+                .await,
+            Err(Error::InexactConversion)
+        ));
+        assert!(matches!(
+            usd.exchange(
+                1,
+                HOUSE,
+                "fx-1".to_string(),
+                100.into(),
+                DEFAULT_ASSET,
+                rate
+            )
+            .await,
+            Err(Error::InvalidAmount)
+        ));
+
+        let tx_id = usd
+            .exchange(1, HOUSE, "fx-1".to_string(), 1_000_000.into(), EUR, rate)
+            .await
+            .expect("exchange should succeed");
+
+        let tx = usd
+            .storage
+            .get_tx_by_reference(&(1, AccountType::Main, EUR).into(), &"fx-1".into())
+            .await
+            .expect("get_tx_by_reference should succeed")
+            .expect("exchange should be indexed for the bought asset");
+        assert_eq!(tx.id(), tx_id);
+        assert_eq!(tx.rate(), Some(rate));
+
+        let balances = usd.get_balances(1).await.expect("get_balances");
+        assert_eq!(*balances[&DEFAULT_ASSET].available, 0);
+        assert_eq!(*balances[&EUR].available, 921_500);
+        let balances = usd.get_balances(HOUSE).await.expect("get_balances");
+        assert_eq!(*balances[&DEFAULT_ASSET].available, 1_000_000);
+        assert_eq!(*balances[&EUR].available, 78_500);
+
+        // The house cannot sell more than it holds
+        usd.deposit(2, "deposit-1".to_string(), 1_000_000.into())
+            .await
+            .expect("deposit should succeed");
+        assert!(matches!(
+            usd.exchange(2, HOUSE, "fx-2".to_string(), 1_000_000.into(), EUR, rate)
+                .await,
+            Err(Error::NotEnough)
+        ));
+
+        let trial_balance = usd.trial_balance().await.expect("trial_balance");
+        assert_eq!(*trial_balance.totals[&DEFAULT_ASSET], 0);
+        assert_eq!(*trial_balance.totals[&EUR], 0);
+    }
 }
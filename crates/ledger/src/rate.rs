@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Amount;
+
+/// Exchange rate between two assets, as an exact fraction.
+///
+/// `base` units of the asset sold buy `quote` units of the asset bought, both in the lowest
+/// denomination of their asset. A rate of 0.9215 EUR per USD, both with 4 decimals, is
+/// `base = 10_000` and `quote = 9_215`. Fractions keep conversions exact, floating point rates
+/// would silently round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rate {
+    /// Amount of the asset sold.
+    pub base: Amount,
+    /// Amount of the asset bought for `base`.
+    pub quote: Amount,
+}
+
+impl Rate {
+    /// Converts an amount of the asset sold into the asset bought.
+    ///
+    /// Returns `None` when the result is not a whole amount, as rounding it either way would
+    /// create or destroy value, when either side of the rate is not positive, or on overflow.
+    pub fn convert(&self, amount: i128) -> Option<i128> {
+        if *self.base <= 0 || *self.quote <= 0 {
+            return None;
+        }
+
+        let scaled = amount.checked_mul(*self.quote)?;
+        if scaled % *self.base != 0 {
+            return None;
+        }
+
+        Some(scaled / *self.base)
+    }
+
+    /// Serializes the rate for hashing, base then quote.
+    pub(crate) fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[..16].copy_from_slice(&self.base.to_bytes());
+        bytes[16..].copy_from_slice(&self.quote.to_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_is_exact_or_nothing() {
+        let rate = Rate {
+            base: 10_000.into(),
+            quote: 9_215.into(),
+        };
+
+        assert_eq!(rate.convert(1_000_000), Some(921_500));
+        // 1 * 0.9215 is not a whole amount
+        assert_eq!(rate.convert(1), None);
+        assert_eq!(rate.convert(i128::MAX), None);
+
+        let rate = Rate {
+            base: 0.into(),
+            quote: 1.into(),
+        };
+        assert_eq!(rate.convert(100), None);
+    }
+}
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+
+/// Who created a reference and why.
+///
+/// Internal operations (disputes, change outputs, etc.) derive their references from the client
+/// reference they act upon. Keeping the kind next to the external id puts them in separate
+/// namespaces, so a client can never collide with or spoof an internal reference.
+#[derive(Debug, Copy, Hash, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Kind {
+    /// Reference supplied by an external client (deposit, withdrawal, movement, etc.).
+    Client,
+    /// A dispute opened against a client transaction.
+    Dispute,
+    /// The resolution of a dispute in favor of the account holder.
+    Resolve,
+    /// The chargeback that closes a dispute.
+    Chargeback,
+    /// An intermediate transaction that splits UTXOs to produce change.
+    Change,
+    /// Administrative and housekeeping operations performed by the ledger operator.
+    System,
+    /// A draw on the account's credit line backing a client withdrawal.
+    Credit,
+    /// The capture of an authorisation hold.
+    Capture,
+    /// The release of an authorisation hold without capturing it.
+    Void,
+}
+
+impl Kind {
+    /// Serializes the kind to a single byte for storage and hashing.
+    ///
+    /// Using fixed byte values keeps the encoding stable if variants are reordered.
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            Kind::Client => 0,
+            Kind::Dispute => 1,
+            Kind::Resolve => 2,
+            Kind::Chargeback => 3,
+            Kind::Change => 4,
+            Kind::System => 5,
+            Kind::Credit => 6,
+            Kind::Capture => 7,
+            Kind::Void => 8,
+        }
+    }
+}
+
+/// A unique identifier for a transaction within an account's context.
+///
+/// References allow external systems to idempotently track transactions and enable
+/// lookups for dispute resolution. Each reference must be unique per account.
+///
+/// A reference is a kind plus an external id. Only client references can be built from outside
+/// the crate (`From<String>` and `From<&str>`); the other kinds are derived by the ledger itself.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Reference {
+    kind: Kind,
+    id: String,
+}
+
+impl From<String> for Reference {
+    fn from(id: String) -> Self {
+        Reference {
+            kind: Kind::Client,
+            id,
+        }
+    }
+}
+
+impl From<&str> for Reference {
+    fn from(id: &str) -> Self {
+        id.to_owned().into()
+    }
+}
+
+impl Reference {
+    /// Creates a reference of any kind, only the ledger creates non-client references.
+    pub(crate) fn new(kind: Kind, id: String) -> Self {
+        Reference { kind, id }
+    }
+
+    /// Derives a reference of another kind for the same external id.
+    pub(crate) fn to_kind(&self, kind: Kind) -> Self {
+        Reference {
+            kind,
+            id: self.id.clone(),
+        }
+    }
+
+    /// Returns who created this reference.
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// Returns the external id.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Serializes to bytes for hashing.
+    ///
+    /// Kinds that existed when references were plain strings serialize to that string, see
+    /// `legacy_bytes`. Later kinds use 1 byte (Kind) + 8 bytes (id length, little-endian) + id,
+    /// the length prefix keeps the encoding unambiguous when the reference is hashed next to
+    /// other fields.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if let Some(bytes) = self.legacy_bytes() {
+            return bytes;
+        }
+
+        let mut bytes = Vec::with_capacity(9 + self.id.len());
+        bytes.push(self.kind.to_byte());
+        bytes.extend_from_slice(&(self.id.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes
+    }
+
+    /// The string this reference was written as before references had a kind: client
+    /// references as is, internal ones with a prefix. Hashing it keeps the IDs of transactions
+    /// stored back then valid. Kinds introduced later have no legacy form.
+    fn legacy_bytes(&self) -> Option<Vec<u8>> {
+        let prefix = match self.kind {
+            Kind::Client => "",
+            Kind::Dispute => "dispute:",
+            Kind::Resolve => "resolved:",
+            Kind::Chargeback => "chargeback:",
+            Kind::Change => "Exchange for ",
+            Kind::System | Kind::Credit | Kind::Capture | Kind::Void => return None,
+        };
+        Some([prefix.as_bytes(), self.id.as_bytes()].concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_references_from_strings() {
+        let reference = Reference::from("deposit-1");
+        assert_eq!(reference.kind(), Kind::Client);
+        assert_eq!(reference.id(), "deposit-1");
+        assert_eq!(reference, Reference::from("deposit-1".to_string()));
+    }
+
+    #[test]
+    fn kinds_do_not_collide() {
+        let client = Reference::from("42");
+        let dispute = client.to_kind(Kind::Dispute);
+
+        assert_ne!(client, dispute);
+        assert_ne!(client.to_bytes(), dispute.to_bytes());
+        assert_eq!(dispute.id(), "42");
+
+        // A client cannot reproduce an internal reference by prefixing the id
+        assert_ne!(Reference::from("dispute:42"), dispute);
+    }
+
+    #[test]
+    fn legacy_kinds_hash_as_their_old_strings() {
+        assert_eq!(Reference::from("ab").to_bytes(), b"ab");
+        assert_eq!(
+            Reference::new(Kind::Dispute, "ab".to_owned()).to_bytes(),
+            b"dispute:ab"
+        );
+        assert_eq!(
+            Reference::new(Kind::Change, "ab".to_owned()).to_bytes(),
+            b"Exchange for ab"
+        );
+    }
+
+    #[test]
+    fn to_bytes_is_length_prefixed() {
+        let bytes = Reference::new(Kind::System, "ab".to_owned()).to_bytes();
+        assert_eq!(bytes[0], Kind::System.to_byte());
+        assert_eq!(&bytes[1..9], &2u64.to_le_bytes());
+        assert_eq!(&bytes[9..], b"ab");
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+/// How ledger operations are retried when a concurrent operation spends the UTXOs they selected.
+///
+/// Every operation that spends funds reads its inputs first and stores the transaction later.
+/// If another operation on the same account commits in between, the storage layer rejects the
+/// stale inputs and the operation is attempted again with a fresh selection, waiting a bit
+/// longer before each new attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, the first one included.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled on every following retry.
+    pub initial_backoff: Duration,
+    /// Upper bound for the wait between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that gives up on the first conflict.
+    pub fn no_retry() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Returns how long to wait before the given retry, starting at 0 for the first one.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(2),
+            max_backoff: Duration::from_millis(10),
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(2));
+        assert_eq!(policy.backoff(1), Duration::from_millis(4));
+        assert_eq!(policy.backoff(2), Duration::from_millis(8));
+        assert_eq!(policy.backoff(3), Duration::from_millis(10));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(10));
+    }
+}
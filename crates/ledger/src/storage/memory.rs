@@ -1,10 +1,11 @@
 //! In memory implementation to show that I know how DB works internally.
-use crate::{FullAccount, Reference, transaction::UtxoId};
+use crate::{AccountId, AccountType, AssetId, FullAccount, Reference, asset, transaction::UtxoId};
 
 use futures::Stream;
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockReadGuard};
 use std::{
-    collections::{BTreeMap, HashMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ops::Bound,
     sync::Arc,
     task::Poll,
 };
@@ -14,10 +15,11 @@ use crate::{
     transaction::{HashId, Transaction, Utxo},
 };
 
-use super::{Error, Storage};
+use super::{Error, HistoryQuery, Order, Snapshot, Storage, UtxoOrder};
 
 #[derive(Debug, Clone, Copy)]
 struct UtxoInMemory {
+    account: FullAccount,
     amount: Amount,
     spent_at: Option<HashId>,
 }
@@ -28,9 +30,274 @@ struct InMemoryStorage {
     utxo_by_account: HashMap<FullAccount, VecDeque<UtxoId>>,
     txs_by_account: BTreeMap<FullAccount, VecDeque<HashId>>,
     txs_by_reference: HashMap<(FullAccount, Reference), HashId>,
+    /// Transactions each account took part in, sorted by time, with the sub-accounts involved
+    history: BTreeMap<HistoryKey, BTreeSet<AccountType>>,
+    /// Running sum of the unspent UTXOs of each account
+    balances: HashMap<FullAccount, i128>,
+    credit_limits: HashMap<FullAccount, Amount>,
     txs: HashMap<HashId, Transaction>,
 }
 
+type HistoryKey = (AccountId, u64, HashId);
+
+/// Whether a range can be passed to `BTreeMap::range`, which panics on inverted ranges.
+fn is_valid_range(lower: &Bound<HistoryKey>, upper: &Bound<HistoryKey>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(start), Bound::Included(end)) => start <= end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start < end,
+        _ => true,
+    }
+}
+
+/// Changes of a batch that are validated but not persisted yet, so later transactions of the
+/// batch can spend the outputs of earlier ones.
+#[derive(Debug, Default)]
+struct PendingBatch {
+    txs: HashSet<HashId>,
+    created: HashMap<UtxoId, (FullAccount, Amount)>,
+    spent: HashSet<UtxoId>,
+    references: HashSet<(FullAccount, Reference)>,
+    balances: HashMap<FullAccount, i128>,
+}
+
+impl InMemoryStorage {
+    /// Checks a transaction can be stored on top of the storage and the pending batch, and
+    /// returns every account that spends or receives funds in it.
+    fn validate(
+        &self,
+        batch: &mut PendingBatch,
+        tx: &Transaction,
+    ) -> Result<BTreeSet<FullAccount>, Error> {
+        let tx_id = tx.id();
+
+        // Is it a duplicate tx?
+        if self.txs.contains_key(&tx_id) || !batch.txs.insert(tx_id) {
+            return Err(Error::Duplicate);
+        }
+
+        // every account that spends or receives funds in this tx
+        let mut accounts = BTreeSet::new();
+
+        // check all the utxo are indeed unspent, either stored or created earlier in the batch
+        for input in tx.inputs() {
+            let (account, amount) = if let Some(utxo) = self.utxo.get(&input.id()) {
+                if utxo.spent_at.is_some() {
+                    return Err(Error::SpentUtxo(input.id()));
+                }
+                (utxo.account, utxo.amount)
+            } else if let Some(created) = batch.created.get(&input.id()) {
+                *created
+            } else {
+                return Err(Error::MissingUtxo(input.id()));
+            };
+
+            if !batch.spent.insert(input.id()) {
+                return Err(Error::SpentUtxo(input.id()));
+            }
+
+            if amount != input.amount() {
+                return Err(Error::MismatchAmount);
+            }
+
+            if account.asset() != input.asset() {
+                return Err(Error::MismatchAsset);
+            }
+
+            accounts.insert(account);
+            self.add_to_balance(batch, account, -*amount)?;
+        }
+
+        accounts.extend(tx.outputs().iter().map(|(account, _)| *account));
+
+        for account in accounts.iter() {
+            let key = (*account, tx.reference());
+            if self.txs_by_reference.contains_key(&key) || !batch.references.insert(key) {
+                return Err(Error::Duplicate);
+            }
+        }
+
+        for (pos, (account, amount)) in tx.outputs().iter().enumerate() {
+            let pos: u8 = pos.try_into().map_err(|_| Error::Math)?;
+            batch
+                .created
+                .insert((tx_id, pos).into(), (*account, *amount));
+            self.add_to_balance(batch, *account, **amount)?;
+        }
+
+        Ok(accounts)
+    }
+
+    /// Lists the unspent UTXOs of an account, newest first, optionally capped to cover a target.
+    fn unspent(
+        &self,
+        account: &FullAccount,
+        target_amount: Option<Amount>,
+    ) -> Result<Vec<Utxo>, Error> {
+        let utxos_for_account = if let Some(utxos) = self.utxo_by_account.get(account) {
+            utxos
+        } else {
+            return Ok(Vec::new());
+        };
+
+        let mut result = Vec::new();
+        let mut total = 0i128;
+
+        for utxo_id in utxos_for_account {
+            let info = self.utxo.get(utxo_id).ok_or(Error::MissingUtxo(*utxo_id))?;
+
+            if info.spent_at.is_some() {
+                continue;
+            }
+
+            result.push(Utxo::new(*utxo_id, info.amount, info.account.asset()));
+            if let Some(target_amount) = target_amount {
+                // We already have enough UTXO to fullfill the request
+                total = total.checked_add(*info.amount).ok_or(Error::Math)?;
+                if *target_amount <= total {
+                    break;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the running balance of an account.
+    fn balance(&self, account: &FullAccount) -> Amount {
+        self.balances
+            .get(account)
+            .copied()
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Returns the credit limit of a Credit sub-account.
+    fn credit_limit(&self, account: &FullAccount) -> Amount {
+        self.credit_limits
+            .get(account)
+            .copied()
+            .unwrap_or_else(|| 0.into())
+    }
+
+    /// Moves the pending balance of an account, starting from the stored one.
+    ///
+    /// Debits to a Credit sub-account fail if they take it past the account's credit limit, and
+    /// credits to a Debt or Credit sub-account fail if they take it above zero.
+    fn add_to_balance(
+        &self,
+        batch: &mut PendingBatch,
+        account: FullAccount,
+        amount: i128,
+    ) -> Result<(), Error> {
+        let stored = self.balances.get(&account).copied().unwrap_or_default();
+        let balance = batch.balances.entry(account).or_insert(stored);
+        *balance = balance.checked_add(amount).ok_or(Error::Math)?;
+
+        if account.typ() == AccountType::Credit
+            && amount < 0
+            && *balance < -*self.credit_limit(&account)
+        {
+            return Err(Error::CreditLimit(account));
+        }
+
+        if matches!(account.typ(), AccountType::Debt | AccountType::Credit)
+            && amount > 0
+            && *balance > 0
+        {
+            return Err(Error::Overpaid(account));
+        }
+
+        Ok(())
+    }
+
+    /// Persists a transaction that passed `validate`.
+    fn apply(&mut self, tx: Transaction, accounts: BTreeSet<FullAccount>) -> Result<(), Error> {
+        let tx_id = tx.id();
+        self.txs.insert(tx_id, tx.clone());
+
+        // mark the input utxo as spent by this transaction
+        for input in tx.inputs() {
+            let in_memory_utxo = if let Some(utxo) = self.utxo.get_mut(&input.id()) {
+                utxo
+            } else {
+                unreachable!();
+            };
+            in_memory_utxo.spent_at = Some(tx_id);
+        }
+
+        // the reference is taken for every account involved, spenders included
+        for account in accounts {
+            self.txs_by_reference
+                .insert((account, tx.reference()), tx_id);
+            self.history
+                .entry((account.id(), tx.timestamp(), tx_id))
+                .or_default()
+                .insert(account.typ());
+        }
+
+        // create the new utxo
+        for (pos, (account, amount)) in tx.outputs().iter().enumerate() {
+            self.txs_by_account
+                .entry(*account)
+                .or_default()
+                .push_front(tx_id);
+
+            let pos = pos.try_into().map_err(|_| Error::Math)?;
+            let utxo_id = (tx_id, pos).into();
+
+            // store the new utxo
+            self.utxo.insert(
+                utxo_id,
+                UtxoInMemory {
+                    account: *account,
+                    amount: *amount,
+                    spent_at: None,
+                },
+            );
+            // add the utxo to the account
+            self.utxo_by_account
+                .entry(*account)
+                .or_default()
+                .push_front(utxo_id);
+        }
+
+        Ok(())
+    }
+}
+
+/// A read guard over the whole storage, writers wait until the reads are done.
+pub struct MemorySnapshot<'a> {
+    inner: RwLockReadGuard<'a, InMemoryStorage>,
+}
+
+impl Snapshot for MemorySnapshot<'_> {
+    fn get_balance(&self, account: &FullAccount) -> Result<Amount, Error> {
+        Ok(self.inner.balance(account))
+    }
+
+    fn get_unspent(&self, account: &FullAccount) -> Result<Vec<Utxo>, Error> {
+        self.inner.unspent(account, None)
+    }
+
+    fn get_credit_limit(&self, account: &FullAccount) -> Result<Amount, Error> {
+        Ok(self.inner.credit_limit(account))
+    }
+
+    fn get_assets(&self, account: AccountId) -> Result<BTreeSet<AssetId>, Error> {
+        let start = FullAccount::from((account, AccountType::Main, asset::DEFAULT));
+        Ok(self
+            .inner
+            .txs_by_account
+            .range(start..)
+            .take_while(|(full_account, _)| full_account.id() == account)
+            .map(|(full_account, _)| full_account.asset())
+            .collect())
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Memory {
     inner: Arc<RwLock<InMemoryStorage>>,
@@ -80,6 +347,15 @@ impl Storage for Memory {
         &self,
         account: &FullAccount,
         target_amount: Option<Amount>,
+    ) -> Result<Vec<Utxo>, Error> {
+        self.inner.read().unspent(account, target_amount)
+    }
+
+    async fn get_unspent_sorted(
+        &self,
+        account: &FullAccount,
+        order: UtxoOrder,
+        target_amount: Option<Amount>,
     ) -> Result<Vec<Utxo>, Error> {
         let inner = self.inner.read();
 
@@ -89,8 +365,68 @@ impl Storage for Memory {
             return Ok(Vec::new());
         };
 
+        // the deque keeps the newest utxo at the front
+        let mut candidates = Vec::new();
+        for utxo_id in utxos_for_account.iter().rev() {
+            let info = inner
+                .utxo
+                .get(utxo_id)
+                .ok_or(Error::MissingUtxo(*utxo_id))?;
+
+            if info.spent_at.is_none() {
+                candidates.push(Utxo::new(*utxo_id, info.amount, info.account.asset()));
+            }
+        }
+
+        // stable sorts, so ties stay oldest first
+        match order {
+            UtxoOrder::OldestFirst => {}
+            UtxoOrder::NewestFirst => candidates.reverse(),
+            UtxoOrder::LargestFirst => {
+                candidates.sort_by_key(|utxo| std::cmp::Reverse(*utxo.amount()))
+            }
+            UtxoOrder::SmallestFirst => candidates.sort_by_key(|utxo| *utxo.amount()),
+        }
+
+        let Some(target_amount) = target_amount else {
+            return Ok(candidates);
+        };
+
         let mut result = Vec::new();
         let mut total = 0i128;
+        for utxo in candidates {
+            if *target_amount <= total {
+                break;
+            }
+            total = total.checked_add(*utxo.amount()).ok_or(Error::Math)?;
+            result.push(utxo);
+        }
+
+        Ok(result)
+    }
+
+    async fn get_unspent_at(
+        &self,
+        account: &FullAccount,
+        timestamp: u64,
+    ) -> Result<Vec<Utxo>, Error> {
+        let inner = self.inner.read();
+
+        let utxos_for_account = if let Some(utxos) = inner.utxo_by_account.get(account) {
+            utxos
+        } else {
+            return Ok(Vec::new());
+        };
+
+        let tx_timestamp = |tx_id: &HashId| {
+            inner
+                .txs
+                .get(tx_id)
+                .map(|tx| tx.timestamp())
+                .ok_or(Error::Internal)
+        };
+
+        let mut result = Vec::new();
 
         for utxo_id in utxos_for_account {
             let info = inner
@@ -98,23 +434,51 @@ impl Storage for Memory {
                 .get(utxo_id)
                 .ok_or(Error::MissingUtxo(*utxo_id))?;
 
-            if info.spent_at.is_some() {
+            // created after the requested instant
+            if tx_timestamp(&utxo_id.hash_id())? > timestamp {
                 continue;
             }
 
-            result.push(Utxo::new(*utxo_id, info.amount));
-            if let Some(target_amount) = target_amount {
-                // We already have enough UTXO to fullfill the request
-                total = total.checked_add(*info.amount).ok_or(Error::Math)?;
-                if *target_amount <= total {
-                    break;
-                }
+            // already spent at the requested instant
+            if let Some(spent_at) = info.spent_at
+                && tx_timestamp(&spent_at)? <= timestamp
+            {
+                continue;
             }
+
+            result.push(Utxo::new(*utxo_id, info.amount, info.account.asset()));
         }
 
         Ok(result)
     }
 
+    async fn get_balance(&self, account: &FullAccount) -> Result<Amount, Error> {
+        Ok(self.inner.read().balance(account))
+    }
+
+    async fn get_credit_limit(&self, account: &FullAccount) -> Result<Amount, Error> {
+        Ok(self.inner.read().credit_limit(account))
+    }
+
+    async fn set_credit_limit(&self, account: &FullAccount, limit: Amount) -> Result<(), Error> {
+        self.inner.write().credit_limits.insert(*account, limit);
+        Ok(())
+    }
+
+    async fn snapshot<T, F>(&self, reads: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Snapshot) -> Result<T, Error> + Send + 'static,
+    {
+        reads(&MemorySnapshot {
+            inner: self.inner.read(),
+        })
+    }
+
+    async fn get_tx(&self, tx_id: &HashId) -> Result<Option<Transaction>, Error> {
+        Ok(self.inner.read().txs.get(tx_id).cloned())
+    }
+
     async fn get_tx_by_reference(
         &self,
         account: &FullAccount,
@@ -132,85 +496,73 @@ impl Storage for Memory {
         Ok(Some(inner.txs.get(tx_id).ok_or(Error::Internal)?.clone()))
     }
 
-    async fn store_tx(&self, tx: Transaction) -> Result<(), Error> {
-        let mut inner = self.inner.write();
-
-        let tx_id = tx.id();
+    async fn get_history(
+        &self,
+        account: AccountId,
+        sub_account: Option<AccountType>,
+        query: &HistoryQuery,
+    ) -> Result<Vec<Transaction>, Error> {
+        let inner = self.inner.read();
 
-        // Is it a duplicate tx?
-        if inner.txs.contains_key(&tx_id) {
-            return Err(Error::Duplicate);
-        }
+        let since = (account, query.since.unwrap_or(0), [0u8; 32]);
+        let mut lower = Bound::Included(since);
+        let mut upper = match query.until {
+            Some(until) => Bound::Excluded((account, until, [0u8; 32])),
+            None => Bound::Included((account, u64::MAX, [u8::MAX; 32])),
+        };
 
-        for (account, _) in tx.outputs().iter() {
-            if inner
-                .txs_by_reference
-                .contains_key(&(*account, tx.reference()))
-            {
-                return Err(Error::Duplicate);
+        // the cursor narrows the end of the range the pagination moves towards
+        if let Some(cursor) = query.after {
+            let key = (account, cursor.timestamp(), cursor.tx_id());
+            match query.order {
+                Order::OldestFirst if key >= since => lower = Bound::Excluded(key),
+                Order::NewestFirst
+                    if query
+                        .until
+                        .is_none_or(|until| key < (account, until, [0u8; 32])) =>
+                {
+                    upper = Bound::Excluded(key)
+                }
+                _ => {}
             }
         }
 
-        // check all the utxo are indeed unspent
-        for input in tx.inputs() {
-            let in_memory_utxo = if let Some(utxo) = inner.utxo.get(&input.id()) {
-                utxo
-            } else {
-                return Err(Error::MissingUtxo(input.id()));
-            };
-
-            if in_memory_utxo.spent_at.is_some() {
-                return Err(Error::SpentUtxo(input.id()));
-            }
-
-            if in_memory_utxo.amount != input.amount() {
-                return Err(Error::MismatchAmount);
-            }
+        if !is_valid_range(&lower, &upper) {
+            return Ok(Vec::new());
         }
 
-        // All check passed, now do the persistence
-        inner.txs.insert(tx_id, tx.clone());
+        let range = inner.history.range((lower, upper));
+        let entries: Box<dyn Iterator<Item = _>> = match query.order {
+            Order::OldestFirst => Box::new(range),
+            Order::NewestFirst => Box::new(range.rev()),
+        };
 
-        // mark the input utxo as spent by this transaction
-        for input in tx.inputs() {
-            let in_memory_utxo = if let Some(utxo) = inner.utxo.get_mut(&input.id()) {
-                utxo
-            } else {
-                unreachable!();
-            };
-            in_memory_utxo.spent_at = Some(tx_id);
-        }
+        entries
+            .filter(|(_, types)| sub_account.is_none_or(|typ| types.contains(&typ)))
+            .take(query.limit)
+            .map(|((_, _, tx_id), _)| inner.txs.get(tx_id).cloned().ok_or(Error::Internal))
+            .collect()
+    }
 
-        // create the new utxo
-        for (pos, (account, amount)) in tx.outputs().iter().enumerate() {
-            inner
-                .txs_by_account
-                .entry(*account)
-                .or_default()
-                .push_front(tx_id);
+    async fn store_tx(&self, tx: Transaction) -> Result<(), Error> {
+        self.store_txs(vec![tx]).await
+    }
 
-            inner
-                .txs_by_reference
-                .insert((*account, tx.reference()), tx_id);
+    async fn store_txs(&self, txs: Vec<Transaction>) -> Result<(), Error> {
+        let mut inner = self.inner.write();
 
-            let pos = pos.try_into().map_err(|_| Error::Math)?;
-            let utxo_id = (tx_id, pos).into();
+        // Every transaction is validated before anything is persisted, so a failure leaves no
+        // trace of the batch
+        let mut batch = PendingBatch::default();
+        let accounts = txs
+            .iter()
+            .map(|tx| inner.validate(&mut batch, tx))
+            .collect::<Result<Vec<_>, _>>()?;
 
-            // store the new utxo
-            inner.utxo.insert(
-                utxo_id,
-                UtxoInMemory {
-                    amount: *amount,
-                    spent_at: None,
-                },
-            );
-            // add the utxo to the account
-            inner
-                .utxo_by_account
-                .entry(*account)
-                .or_default()
-                .push_front(utxo_id);
+        for (tx, accounts) in txs.into_iter().zip(accounts) {
+            inner.apply(tx, accounts)?;
         }
+        inner.balances.extend(batch.balances);
 
         Ok(())
     }
@@ -221,4 +573,33 @@ mod tests {
     use super::*;
 
     crate::storage_test!(Memory::default());
+
+    #[tokio::test]
+    async fn test_verify_balances_reports_drift() {
+        let storage = Memory::default();
+        let inner = storage.inner.clone();
+        let ledger = crate::Ledger::new(storage);
+
+        ledger
+            .deposit(1, "deposit-1".to_string(), 100.into())
+            .await
+            .expect("deposit should succeed");
+        assert!(ledger.verify_balances().await.unwrap().is_empty());
+
+        // Corrupt the running balance behind the ledger's back
+        inner.write().balances.insert(1.into(), 90);
+
+        let drifts = ledger
+            .verify_balances()
+            .await
+            .expect("verify_balances should succeed");
+        assert_eq!(
+            drifts,
+            vec![crate::BalanceDrift {
+                account: 1.into(),
+                cached: 90.into(),
+                actual: 100.into(),
+            }]
+        );
+    }
 }
@@ -1,38 +1,186 @@
-use crate::transaction::{Transaction, Utxo, UtxoId};
-use crate::{FullAccount, Reference};
+use crate::transaction::{HashId, Transaction, Utxo, UtxoId};
+use crate::{AccountId, AccountType, AssetId, FullAccount, Reference};
 
 use super::Amount;
+use std::collections::BTreeSet;
 
 mod memory;
 #[cfg(feature = "sqlite")]
 mod sqlite;
 
 use futures::Stream;
+use serde::{Deserialize, Serialize};
+
 pub use memory::Memory;
 #[cfg(feature = "sqlite")]
-pub use sqlite::Sqlite;
+pub use sqlite::{OpenError, Sqlite};
+
+/// Source of an error raised by a storage backend.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
+/// Errors raised by the storage layer, see [`crate::Error::Storage`].
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    /// An input spends a UTXO that was never created.
     #[error("Missing utxo {0:?}")]
     MissingUtxo(UtxoId),
 
+    /// An input spends a UTXO that was already spent.
     #[error("Spent utxo {0:?}")]
     SpentUtxo(UtxoId),
 
+    /// An input carries a different amount than the UTXO it spends.
     #[error("Mismatch amount between the stored utxo and the tx utxo")]
     MismatchAmount,
 
+    /// An input carries a different asset than the UTXO it spends.
+    #[error("Mismatch asset between the stored utxo and the tx utxo")]
+    MismatchAsset,
+
+    /// Adding up amounts overflowed, or a transaction has too many outputs.
     #[error("Math error")]
     Math,
 
+    /// The transaction, or its reference on one of its accounts, was already stored.
     #[error("Duplicate")]
     Duplicate,
 
+    /// The transaction draws more than the credit limit of the sub-account.
+    #[error("Credit limit exceeded for {0:?}")]
+    CreditLimit(FullAccount),
+
+    /// The transaction repays more than the sub-account owes.
+    #[error("Repayment exceeds what {0:?} owes")]
+    Overpaid(FullAccount),
+
+    /// The backend could not be reached or failed to read or write, e.g. an I/O error or a
+    /// database locked for too long.
+    #[error("Storage backend error: {0}")]
+    Backend(#[source] BoxError),
+
+    /// The backend refused a write that breaks one of its constraints.
+    #[error("Constraint violation: {0}")]
+    Constraint(#[source] BoxError),
+
+    /// A stored value cannot be decoded, `key` names where it was read from.
+    #[error("Corrupt data at {key}: {source}")]
+    Corrupt {
+        /// Table and key of the row holding the value.
+        key: String,
+        /// Why the value cannot be decoded.
+        #[source]
+        source: BoxError,
+    },
+
+    /// A transaction could not be serialized or deserialized.
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    /// An invariant of the storage layer does not hold.
     #[error("Error internal")]
     Internal,
 }
 
+/// Order in which an account's history is returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Order {
+    /// Most recent transactions first.
+    #[default]
+    NewestFirst,
+    /// Oldest transactions first.
+    OldestFirst,
+}
+
+/// Order in which unspent UTXOs are listed for coin selection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UtxoOrder {
+    /// In the order they were created.
+    #[default]
+    OldestFirst,
+    /// Most recently created first.
+    NewestFirst,
+    /// Highest amount first, ties broken by age.
+    LargestFirst,
+    /// Lowest amount first, ties broken by age.
+    SmallestFirst,
+}
+
+/// A position in an account's history.
+///
+/// History is ordered by transaction timestamp, with ties broken by transaction ID, so a cursor
+/// is stable even if new transactions are stored while paginating.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Cursor {
+    timestamp: u64,
+    tx_id: HashId,
+}
+
+impl From<&Transaction> for Cursor {
+    fn from(tx: &Transaction) -> Self {
+        Cursor {
+            timestamp: tx.timestamp(),
+            tx_id: tx.id(),
+        }
+    }
+}
+
+impl Cursor {
+    /// Returns the timestamp of the transaction this cursor points to.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Returns the ID of the transaction this cursor points to.
+    pub fn tx_id(&self) -> HashId {
+        self.tx_id
+    }
+}
+
+/// Filters and pagination for an account's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryQuery {
+    /// Direction of the pagination.
+    pub order: Order,
+    /// Only transactions at or after this timestamp (microseconds).
+    pub since: Option<u64>,
+    /// Only transactions strictly before this timestamp (microseconds).
+    pub until: Option<u64>,
+    /// Continue after this transaction, as returned by the previous page.
+    pub after: Option<Cursor>,
+    /// Maximum number of transactions per page.
+    pub limit: usize,
+}
+
+impl Default for HistoryQuery {
+    fn default() -> Self {
+        HistoryQuery {
+            order: Order::default(),
+            since: None,
+            until: None,
+            after: None,
+            limit: 100,
+        }
+    }
+}
+
+/// A consistent read view of the storage, see `Storage::snapshot`.
+///
+/// Every read through a snapshot observes the same committed state, transactions stored while
+/// it is open are not visible to it.
+pub trait Snapshot {
+    /// Get the running balance of an account, see `Storage::get_balance`.
+    fn get_balance(&self, account: &FullAccount) -> Result<Amount, Error>;
+
+    /// Get every unspent UTXO of an account, in the same order as `Storage::get_unspent`.
+    fn get_unspent(&self, account: &FullAccount) -> Result<Vec<Utxo>, Error>;
+
+    /// Get the credit limit of a Credit sub-account, see `Storage::get_credit_limit`.
+    fn get_credit_limit(&self, account: &FullAccount) -> Result<Amount, Error>;
+
+    /// Get every asset any sub-account of an account has taken part in a transaction with.
+    fn get_assets(&self, account: AccountId) -> Result<BTreeSet<AssetId>, Error>;
+}
+
 /// Extremely simple storage layer
 ///
 /// All math is not done, and its sole responsibilities are storage, durability and correctness.
@@ -51,13 +199,80 @@ pub trait Storage {
         target_amount: Option<Amount>,
     ) -> Result<Vec<Utxo>, Error>;
 
+    /// Get unspent UTXO for this given account in the requested order. Optionally it be capped
+    /// to cover a target_amount, in which case the UTXOs are taken in order until their sum
+    /// reaches it.
+    ///
+    /// Ordering is the only smartness of the storage layer, picking which UTXOs are worth
+    /// spending is left to the caller.
+    async fn get_unspent_sorted(
+        &self,
+        account: &FullAccount,
+        order: UtxoOrder,
+        target_amount: Option<Amount>,
+    ) -> Result<Vec<Utxo>, Error>;
+
+    /// Get the UTXOs of an account as they were at `timestamp`.
+    ///
+    /// Returns every UTXO created by a transaction with a timestamp at or before the given
+    /// instant that was not spent by a transaction at or before it, in the same order as
+    /// `get_unspent`.
+    async fn get_unspent_at(
+        &self,
+        account: &FullAccount,
+        timestamp: u64,
+    ) -> Result<Vec<Utxo>, Error>;
+
+    /// Get the balance of an account, the sum of its unspent UTXOs.
+    ///
+    /// Backends keep a running balance per account, updated in the same unit of work that stores
+    /// a transaction, so reading it does not require walking the UTXOs.
+    async fn get_balance(&self, account: &FullAccount) -> Result<Amount, Error>;
+
+    /// Get how far a Credit sub-account may go below zero, zero if never set.
+    async fn get_credit_limit(&self, account: &FullAccount) -> Result<Amount, Error>;
+
+    /// Sets the credit limit of a Credit sub-account.
+    ///
+    /// The limit is enforced when transactions are stored: a transaction that takes the Credit
+    /// sub-account below the negated limit fails with `Error::CreditLimit`. Lowering the limit
+    /// below what is already drawn is allowed, it only blocks further draws.
+    async fn set_credit_limit(&self, account: &FullAccount, limit: Amount) -> Result<(), Error>;
+
+    /// Runs `reads` against a read snapshot, so several reads reflect the same committed state.
+    ///
+    /// The reads are synchronous and may block, backends run them away from the async runtime.
+    /// They are meant to be short, writers may be held back until they return.
+    async fn snapshot<T, F>(&self, reads: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Snapshot) -> Result<T, Error> + Send + 'static;
+
+    /// Get a transaction by its ID.
+    async fn get_tx(&self, tx_id: &HashId) -> Result<Option<Transaction>, Error>;
+
     /// Get transactions by Reference
+    ///
+    /// The account may have either spent or received funds in the returned transaction.
     async fn get_tx_by_reference(
         &self,
         account: &FullAccount,
         reference: &Reference,
     ) -> Result<Option<Transaction>, Error>;
 
+    /// Get the transactions an account took part in, either spending or receiving funds.
+    ///
+    /// Transactions are sorted by timestamp and transaction ID in the requested order, filtered
+    /// by the query time range, start right after the query cursor, and are capped to the query
+    /// limit. Without a sub-account every sub-account of the account is included, and a
+    /// transaction touching several of them is returned once.
+    async fn get_history(
+        &self,
+        account: AccountId,
+        sub_account: Option<AccountType>,
+        query: &HistoryQuery,
+    ) -> Result<Vec<Transaction>, Error>;
+
     /// Returns an iterator with a list of account. An iterator is used to avoid loading the whole
     /// list (which its size is unknown)
     ///
@@ -75,8 +290,22 @@ pub trait Storage {
     /// In the same transaction the transaction is stored and the input UTXO are set as spent. The
     /// entire operations succeeds or it is rollback
     ///
-    /// References are unique per account as has to be enforced
+    /// Credit limits are enforced in the same unit of work, see `set_credit_limit`. Debt and
+    /// Credit sub-accounts record what an account owes, a transaction taking either of them
+    /// above zero fails with `Error::Overpaid`.
+    ///
+    /// References are unique per account as has to be enforced. Every account involved in the
+    /// transaction, either by spending one of the inputs or by receiving one of the outputs, is
+    /// indexed under the transaction's reference. A transaction without outputs (e.g. an unlock)
+    /// can therefore still be found by reference and cannot be replayed.
     async fn store_tx(&self, tx: Transaction) -> Result<(), Error>;
+
+    /// Stores several transactions as a single unit of work
+    ///
+    /// Transactions are validated in order, with the same rules as `store_tx`, and a transaction
+    /// may spend the outputs of the ones before it in the batch. Either every transaction is
+    /// stored or, if any of them fails, none is.
+    async fn store_txs(&self, txs: Vec<Transaction>) -> Result<(), Error>;
 }
 
 #[cfg(test)]
@@ -93,9 +322,10 @@ pub trait Storage {
 #[macro_export]
 macro_rules! storage_test {
     ($storage_expr:expr) => {
-        use $crate::storage::Error;
-        use $crate::transaction::{HashId, Transaction, Utxo};
-        use $crate::{AccountId, AccountType, Amount, FullAccount};
+        use $crate::storage::{Cursor, Error, HistoryQuery, Order, UtxoOrder};
+        use $crate::transaction::{HashId, Transaction, Utxo, UtxoId};
+        use $crate::{AccountId, AccountType, Amount, DEFAULT_ASSET, FullAccount};
+        use std::collections::BTreeSet;
 
         fn make_account(id: AccountId) -> FullAccount {
             id.into()
@@ -110,14 +340,14 @@ macro_rules! storage_test {
             Transaction::new(
                 vec![],
                 vec![(account, amount)],
-                reference.to_string(),
+                reference.into(),
                 Some(timestamp),
             )
             .expect("deposit transaction should be valid")
         }
 
         fn make_utxo(tx_id: HashId, pos: u8, amount: Amount) -> Utxo {
-            Utxo::new((tx_id, pos).into(), amount)
+            Utxo::new((tx_id, pos).into(), amount, DEFAULT_ASSET)
         }
 
         #[tokio::test]
@@ -187,7 +417,7 @@ macro_rules! storage_test {
             let spend_tx = Transaction::new(
                 vec![utxo],
                 vec![(account, amount)],
-                "spend-1".to_string(),
+                "spend-1".into(),
                 Some(2000),
             )
             .expect("spend transaction should be valid");
@@ -201,7 +431,7 @@ macro_rules! storage_test {
             let double_spend_tx = Transaction::new(
                 vec![utxo_again],
                 vec![(account, amount)],
-                "spend-2".to_string(),
+                "spend-2".into(),
                 Some(3000),
             )
             .expect("double spend transaction should be valid structurally");
@@ -221,7 +451,7 @@ macro_rules! storage_test {
             let tx = Transaction::new(
                 vec![utxo],
                 vec![(account, amount)],
-                "spend-1".to_string(),
+                "spend-1".into(),
                 Some(1000),
             )
             .expect("transaction with fake utxo should be valid structurally");
@@ -250,7 +480,7 @@ macro_rules! storage_test {
             let spend_tx = Transaction::new(
                 vec![utxo],
                 vec![(account, wrong_amount)],
-                "spend-1".to_string(),
+                "spend-1".into(),
                 Some(2000),
             )
             .expect("transaction with wrong amount should be valid structurally");
@@ -259,6 +489,64 @@ macro_rules! storage_test {
             assert!(matches!(result, Err(Error::MismatchAmount)));
         }
 
+        #[tokio::test]
+        async fn test_mismatch_asset_error() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+            let amount: Amount = 100.into();
+
+            let deposit_tx = make_deposit_tx(account, amount, "deposit-1", 1000);
+            let deposit_id = deposit_tx.id();
+            storage
+                .store_tx(deposit_tx)
+                .await
+                .expect("deposit should succeed");
+
+            // Claim the UTXO holds another asset, and move it to that asset
+            let other: FullAccount = (1, AccountType::Main, 1).into();
+            let utxo = Utxo::new((deposit_id, 0).into(), amount, 1);
+            let spend_tx = Transaction::new(vec![utxo], vec![(other, amount)], "spend-1".into(), Some(2000))
+                .expect("transaction with wrong asset should be valid structurally");
+
+            let result = storage.store_tx(spend_tx).await;
+            assert!(matches!(result, Err(Error::MismatchAsset)));
+        }
+
+        #[tokio::test]
+        async fn test_assets_kept_apart() {
+            let storage = $storage_expr;
+            let usd = make_account(1);
+            let eur: FullAccount = (1, AccountType::Main, 1).into();
+
+            storage
+                .store_tx(make_deposit_tx(usd, 100.into(), "deposit-1", 1000))
+                .await
+                .expect("usd deposit should succeed");
+            // The same reference is free on the other asset
+            storage
+                .store_tx(make_deposit_tx(eur, 30.into(), "deposit-1", 2000))
+                .await
+                .expect("eur deposit should succeed");
+
+            let unspent = storage
+                .get_unspent(&eur, None)
+                .await
+                .expect("get_unspent should succeed");
+            assert_eq!(unspent.len(), 1);
+            assert_eq!(*unspent[0].amount(), 30);
+            assert_eq!(unspent[0].asset(), 1);
+
+            assert_eq!(*storage.get_balance(&usd).await.expect("balance"), 100);
+            assert_eq!(*storage.get_balance(&eur).await.expect("balance"), 30);
+
+            let (assets, none) = storage
+                .snapshot(|snapshot| Ok((snapshot.get_assets(1)?, snapshot.get_assets(2)?)))
+                .await
+                .expect("snapshot");
+            assert_eq!(assets, BTreeSet::from([DEFAULT_ASSET, 1]));
+            assert!(none.is_empty());
+        }
+
         #[tokio::test]
         async fn test_get_unspent_with_target_amount_exact() {
             let storage = $storage_expr;
@@ -358,7 +646,7 @@ macro_rules! storage_test {
             let spend_tx = Transaction::new(
                 vec![utxo],
                 vec![(account, 100.into())],
-                "spend-1".to_string(),
+                "spend-1".into(),
                 Some(2000),
             )
             .expect("spend transaction should be valid");
@@ -411,6 +699,198 @@ macro_rules! storage_test {
             assert_eq!(*unspent2[0].amount(), 200);
         }
 
+        #[tokio::test]
+        async fn test_get_unspent_sorted_orders() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            let mut ids: Vec<UtxoId> = Vec::new();
+            for (i, amount) in [20, 50, 10, 50].into_iter().enumerate() {
+                let tx = make_deposit_tx(
+                    account,
+                    amount.into(),
+                    &format!("deposit-{i}"),
+                    1000 * (i as u64 + 1),
+                );
+                ids.push((tx.id(), 0).into());
+                storage.store_tx(tx).await.expect("deposit should succeed");
+            }
+
+            // Spent UTXOs are never listed
+            let spend = Transaction::new(
+                vec![make_utxo(ids[2].hash_id(), 0, 10.into())],
+                vec![(make_account(2), 10.into())],
+                "spend-1".into(),
+                Some(5000),
+            )
+            .expect("spend should be valid");
+            storage.store_tx(spend).await.expect("spend should succeed");
+
+            for (order, expected) in [
+                (UtxoOrder::OldestFirst, vec![ids[0], ids[1], ids[3]]),
+                (UtxoOrder::NewestFirst, vec![ids[3], ids[1], ids[0]]),
+                (UtxoOrder::LargestFirst, vec![ids[1], ids[3], ids[0]]),
+                (UtxoOrder::SmallestFirst, vec![ids[0], ids[1], ids[3]]),
+            ] {
+                let unspent = storage
+                    .get_unspent_sorted(&account, order, None)
+                    .await
+                    .expect("get_unspent_sorted should succeed");
+                let unspent = unspent.iter().map(|utxo| utxo.id()).collect::<Vec<_>>();
+                assert_eq!(unspent, expected, "unexpected order for {order:?}");
+            }
+
+            // The target caps the listing in the requested order
+            let unspent = storage
+                .get_unspent_sorted(&account, UtxoOrder::LargestFirst, Some(60.into()))
+                .await
+                .expect("get_unspent_sorted should succeed");
+            let unspent = unspent.iter().map(|utxo| utxo.id()).collect::<Vec<_>>();
+            assert_eq!(unspent, vec![ids[1], ids[3]]);
+
+            let unspent = storage
+                .get_unspent_sorted(&account, UtxoOrder::SmallestFirst, Some(20.into()))
+                .await
+                .expect("get_unspent_sorted should succeed");
+            let unspent = unspent.iter().map(|utxo| utxo.id()).collect::<Vec<_>>();
+            assert_eq!(unspent, vec![ids[0]]);
+        }
+
+        #[tokio::test]
+        async fn test_amounts_round_trip_at_i128_scale() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            let deposit = make_deposit_tx(account, i128::MAX.into(), "deposit-1", 1000);
+            let deposit_id = deposit.id();
+            storage
+                .store_tx(deposit)
+                .await
+                .expect("deposit should succeed");
+
+            let unspent = storage
+                .get_unspent(&account, None)
+                .await
+                .expect("get_unspent should succeed");
+            assert_eq!(*unspent[0].amount(), i128::MAX);
+            assert_eq!(
+                *storage.get_balance(&account).await.expect("get_balance"),
+                i128::MAX
+            );
+
+            // Spending checks the amount at full width
+            let spend = Transaction::new(
+                vec![make_utxo(deposit_id, 0, i128::MAX.into())],
+                vec![
+                    (make_account(2), (i128::MAX - 1).into()),
+                    (make_account(3), 1.into()),
+                ],
+                "spend-1".into(),
+                Some(2000),
+            )
+            .expect("spend should be valid");
+            storage.store_tx(spend).await.expect("spend should succeed");
+            assert_eq!(
+                *storage
+                    .get_balance(&make_account(2))
+                    .await
+                    .expect("get_balance"),
+                i128::MAX - 1
+            );
+
+            // Ordering holds across signs and past the i64 range
+            let account = make_account(4);
+            let amounts = [i64::MAX as i128 + 1, -(i128::MAX / 2), 5, i128::MAX / 2];
+            for (i, amount) in amounts.into_iter().enumerate() {
+                let tx = make_deposit_tx(
+                    account,
+                    amount.into(),
+                    &format!("deposit-{i}"),
+                    1000 * (i as u64 + 1),
+                );
+                storage.store_tx(tx).await.expect("deposit should succeed");
+            }
+
+            let unspent = storage
+                .get_unspent_sorted(&account, UtxoOrder::LargestFirst, None)
+                .await
+                .expect("get_unspent_sorted should succeed");
+            let unspent = unspent
+                .iter()
+                .map(|utxo| *utxo.amount())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                unspent,
+                vec![i128::MAX / 2, i64::MAX as i128 + 1, 5, -(i128::MAX / 2)]
+            );
+            assert_eq!(
+                *storage.get_balance(&account).await.expect("get_balance"),
+                i64::MAX as i128 + 6
+            );
+        }
+
+        #[tokio::test]
+        async fn test_get_unspent_at_past_instants() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+            let other = make_account(2);
+
+            let deposit_a = make_deposit_tx(account, 100.into(), "deposit-a", 1000);
+            let deposit_b = make_deposit_tx(account, 50.into(), "deposit-b", 2000);
+            let withdraw_a = Transaction::new(
+                vec![make_utxo(deposit_a.id(), 0, 100.into())],
+                vec![],
+                "withdraw-a".into(),
+                Some(3000),
+            )
+            .expect("withdrawal should be valid");
+            let move_b = Transaction::new(
+                vec![make_utxo(deposit_b.id(), 0, 50.into())],
+                vec![(other, 20.into()), (account, 30.into())],
+                "move-b".into(),
+                Some(4000),
+            )
+            .expect("movement should be valid");
+
+            for tx in [&deposit_a, &deposit_b, &withdraw_a, &move_b] {
+                storage
+                    .store_tx(tx.clone())
+                    .await
+                    .expect("store_tx should succeed");
+            }
+
+            let utxo_a = (deposit_a.id(), 0).into();
+            let utxo_b = (deposit_b.id(), 0).into();
+            let change = (move_b.id(), 1).into();
+
+            for (timestamp, expected) in [
+                (999, vec![]),
+                (1000, vec![utxo_a]),
+                (2500, vec![utxo_a, utxo_b]),
+                (3000, vec![utxo_b]),
+                (4000, vec![change]),
+                (u64::MAX, vec![change]),
+            ] {
+                let mut unspent = storage
+                    .get_unspent_at(&account, timestamp)
+                    .await
+                    .expect("get_unspent_at should succeed")
+                    .iter()
+                    .map(|utxo| utxo.id())
+                    .collect::<Vec<_>>();
+                unspent.sort();
+                let mut expected = expected;
+                expected.sort();
+                assert_eq!(unspent, expected, "unspent mismatch at {timestamp}");
+            }
+
+            let unspent = storage
+                .get_unspent_at(&other, 3999)
+                .await
+                .expect("get_unspent_at should succeed");
+            assert!(unspent.is_empty());
+        }
+
         #[tokio::test]
         async fn test_get_tx_by_reference_returns_transaction() {
             let storage = $storage_expr;
@@ -421,14 +901,14 @@ macro_rules! storage_test {
             storage.store_tx(tx).await.expect("store_tx should succeed");
 
             let result = storage
-                .get_tx_by_reference(&account, &"deposit-1".to_string())
+                .get_tx_by_reference(&account, &"deposit-1".into())
                 .await
                 .expect("get_tx_by_reference should succeed");
 
             assert!(result.is_some());
             let found_tx = result.unwrap();
             assert_eq!(found_tx.id(), tx_id);
-            assert_eq!(found_tx.reference(), "deposit-1");
+            assert_eq!(found_tx.reference(), "deposit-1".into());
         }
 
         #[tokio::test]
@@ -440,7 +920,7 @@ macro_rules! storage_test {
             storage.store_tx(tx).await.expect("store_tx should succeed");
 
             let result = storage
-                .get_tx_by_reference(&account, &"nonexistent".to_string())
+                .get_tx_by_reference(&account, &"nonexistent".into())
                 .await
                 .expect("get_tx_by_reference should succeed");
 
@@ -458,7 +938,7 @@ macro_rules! storage_test {
 
             // Try to get the transaction with the right reference but wrong account
             let result = storage
-                .get_tx_by_reference(&account2, &"deposit-1".to_string())
+                .get_tx_by_reference(&account2, &"deposit-1".into())
                 .await
                 .expect("get_tx_by_reference should succeed");
 
@@ -504,11 +984,11 @@ macro_rules! storage_test {
 
             // Verify both transactions exist
             let result1 = storage
-                .get_tx_by_reference(&account1, &"deposit-1".to_string())
+                .get_tx_by_reference(&account1, &"deposit-1".into())
                 .await
                 .expect("get_tx_by_reference should succeed");
             let result2 = storage
-                .get_tx_by_reference(&account2, &"deposit-1".to_string())
+                .get_tx_by_reference(&account2, &"deposit-1".into())
                 .await
                 .expect("get_tx_by_reference should succeed");
 
@@ -524,7 +1004,7 @@ macro_rules! storage_test {
             let account = make_account(1);
 
             let result = storage
-                .get_tx_by_reference(&account, &"any-reference".to_string())
+                .get_tx_by_reference(&account, &"any-reference".into())
                 .await
                 .expect("get_tx_by_reference should succeed for empty storage");
 
@@ -571,7 +1051,7 @@ macro_rules! storage_test {
             let spend_tx = Transaction::new(
                 vec![utxo_b],
                 vec![(account, 50.into())],
-                "spend-b".to_string(),
+                "spend-b".into(),
                 Some(3000),
             )
             .expect("spend transaction should be valid");
@@ -674,5 +1154,689 @@ macro_rules! storage_test {
             assert_eq!(accounts[2].id(), 2);
             assert_eq!(accounts[2].typ(), AccountType::Disputed);
         }
+
+        #[tokio::test]
+        async fn test_reference_indexed_for_spender_without_outputs() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            let deposit_tx = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            let deposit_id = deposit_tx.id();
+            storage
+                .store_tx(deposit_tx)
+                .await
+                .expect("deposit should succeed");
+
+            // A transaction with inputs and no outputs, like a withdrawal
+            let withdrawal = Transaction::new(
+                vec![make_utxo(deposit_id, 0, 100.into())],
+                vec![],
+                "withdraw-1".into(),
+                Some(2000),
+            )
+            .expect("withdrawal transaction should be valid");
+            let withdrawal_id = withdrawal.id();
+            storage
+                .store_tx(withdrawal)
+                .await
+                .expect("withdrawal should succeed");
+
+            let found = storage
+                .get_tx_by_reference(&account, &"withdraw-1".into())
+                .await
+                .expect("get_tx_by_reference should succeed")
+                .expect("withdrawal should be indexed for the spending account");
+            assert_eq!(found.id(), withdrawal_id);
+        }
+
+        #[tokio::test]
+        async fn test_duplicate_reference_for_spender_rejected() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+            let other = make_account(2);
+
+            let deposit_a = make_deposit_tx(account, 100.into(), "deposit-a", 1000);
+            let deposit_a_id = deposit_a.id();
+            let deposit_b = make_deposit_tx(account, 100.into(), "deposit-b", 2000);
+            let deposit_b_id = deposit_b.id();
+            storage
+                .store_tx(deposit_a)
+                .await
+                .expect("deposit a should succeed");
+            storage
+                .store_tx(deposit_b)
+                .await
+                .expect("deposit b should succeed");
+
+            let first = Transaction::new(
+                vec![make_utxo(deposit_a_id, 0, 100.into())],
+                vec![],
+                "withdraw-1".into(),
+                Some(3000),
+            )
+            .expect("first withdrawal should be valid");
+            storage
+                .store_tx(first)
+                .await
+                .expect("first withdrawal should succeed");
+
+            // Spending other UTXOs of the same account under the same reference is rejected,
+            // even if the outputs go to another account
+            let replay = Transaction::new(
+                vec![make_utxo(deposit_b_id, 0, 100.into())],
+                vec![(other, 100.into())],
+                "withdraw-1".into(),
+                Some(4000),
+            )
+            .expect("replayed withdrawal should be valid structurally");
+            let result = storage.store_tx(replay).await;
+            assert!(matches!(result, Err(Error::Duplicate)));
+
+            // The rejected transaction left no trace
+            let unspent = storage
+                .get_unspent(&account, None)
+                .await
+                .expect("get_unspent should succeed");
+            assert_eq!(unspent.len(), 1);
+            assert_eq!(unspent[0].id(), (deposit_b_id, 0).into());
+            let result = storage
+                .get_tx_by_reference(&other, &"withdraw-1".into())
+                .await
+                .expect("get_tx_by_reference should succeed");
+            assert!(result.is_none());
+        }
+
+        fn tx_ids(txs: &[Transaction]) -> Vec<HashId> {
+            txs.iter().map(|tx| tx.id()).collect()
+        }
+
+        #[tokio::test]
+        async fn test_history_includes_spenders_in_both_orders() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+            let other = make_account(2);
+
+            let deposit_1 = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            let deposit_2 = make_deposit_tx(account, 50.into(), "deposit-2", 2000);
+            let unrelated = make_deposit_tx(other, 10.into(), "deposit-3", 2500);
+            let withdraw = Transaction::new(
+                vec![make_utxo(deposit_1.id(), 0, 100.into())],
+                vec![],
+                "withdraw-1".into(),
+                Some(3000),
+            )
+            .expect("withdrawal should be valid");
+
+            for tx in [&deposit_1, &deposit_2, &unrelated, &withdraw] {
+                storage
+                    .store_tx(tx.clone())
+                    .await
+                    .expect("store_tx should succeed");
+            }
+
+            let history = storage
+                .get_history(1, None, &HistoryQuery::default())
+                .await
+                .expect("get_history should succeed");
+            assert_eq!(
+                tx_ids(&history),
+                vec![withdraw.id(), deposit_2.id(), deposit_1.id()]
+            );
+
+            let query = HistoryQuery {
+                order: Order::OldestFirst,
+                ..Default::default()
+            };
+            let history = storage
+                .get_history(1, None, &query)
+                .await
+                .expect("get_history should succeed");
+            assert_eq!(
+                tx_ids(&history),
+                vec![deposit_1.id(), deposit_2.id(), withdraw.id()]
+            );
+
+            let history = storage
+                .get_history(3, None, &query)
+                .await
+                .expect("get_history should succeed for unknown account");
+            assert!(history.is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_history_paginates_with_cursor() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            // Two transactions share a timestamp, the transaction ID breaks the tie
+            let mut expected = Vec::new();
+            for (i, timestamp) in [1000, 2000, 2000, 3000, 4000].into_iter().enumerate() {
+                let tx = make_deposit_tx(account, 10.into(), &format!("deposit-{i}"), timestamp);
+                expected.push(tx.id());
+                storage.store_tx(tx).await.expect("deposit should succeed");
+            }
+            expected[1..3].sort();
+
+            for order in [Order::OldestFirst, Order::NewestFirst] {
+                let mut query = HistoryQuery {
+                    order,
+                    limit: 2,
+                    ..Default::default()
+                };
+                let mut seen = Vec::new();
+                loop {
+                    let page = storage
+                        .get_history(1, None, &query)
+                        .await
+                        .expect("get_history should succeed");
+                    assert!(page.len() <= 2);
+                    let Some(last) = page.last() else {
+                        break;
+                    };
+                    query.after = Some(Cursor::from(last));
+                    seen.extend(tx_ids(&page));
+                }
+
+                if order == Order::NewestFirst {
+                    seen.reverse();
+                }
+                assert_eq!(seen, expected);
+            }
+        }
+
+        #[tokio::test]
+        async fn test_history_time_range() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            let mut ids = Vec::new();
+            for (i, timestamp) in [1000, 2000, 3000, 4000].into_iter().enumerate() {
+                let tx = make_deposit_tx(account, 10.into(), &format!("deposit-{i}"), timestamp);
+                ids.push(tx.id());
+                storage.store_tx(tx).await.expect("deposit should succeed");
+            }
+
+            // since is inclusive, until is exclusive
+            let query = HistoryQuery {
+                order: Order::OldestFirst,
+                since: Some(2000),
+                until: Some(4000),
+                ..Default::default()
+            };
+            let history = storage
+                .get_history(1, None, &query)
+                .await
+                .expect("get_history should succeed");
+            assert_eq!(tx_ids(&history), vec![ids[1], ids[2]]);
+
+            // A cursor outside of the range does not widen it
+            let query = HistoryQuery {
+                order: Order::NewestFirst,
+                until: Some(3000),
+                after: Some(Cursor::from(&make_deposit_tx(
+                    account,
+                    10.into(),
+                    "deposit-9",
+                    9000,
+                ))),
+                ..Default::default()
+            };
+            let history = storage
+                .get_history(1, None, &query)
+                .await
+                .expect("get_history should succeed");
+            assert_eq!(tx_ids(&history), vec![ids[1], ids[0]]);
+
+            // An empty range is not an error
+            let query = HistoryQuery {
+                since: Some(3000),
+                until: Some(2000),
+                ..Default::default()
+            };
+            let history = storage
+                .get_history(1, None, &query)
+                .await
+                .expect("get_history should succeed for an empty range");
+            assert!(history.is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_history_filters_by_sub_account() {
+            let storage = $storage_expr;
+            let main = make_account(1);
+            let disputed: FullAccount = (1, AccountType::Disputed).into();
+
+            let deposit = make_deposit_tx(main, 100.into(), "deposit-1", 1000);
+            let hold = Transaction::new(
+                vec![make_utxo(deposit.id(), 0, 100.into())],
+                vec![(disputed, 60.into()), (main, 40.into())],
+                "hold-1".into(),
+                Some(2000),
+            )
+            .expect("hold should be valid");
+            storage
+                .store_tx(deposit.clone())
+                .await
+                .expect("deposit should succeed");
+            storage
+                .store_tx(hold.clone())
+                .await
+                .expect("hold should succeed");
+
+            // A transaction touching several sub-accounts is returned once
+            let history = storage
+                .get_history(1, None, &HistoryQuery::default())
+                .await
+                .expect("get_history should succeed");
+            assert_eq!(tx_ids(&history), vec![hold.id(), deposit.id()]);
+
+            let history = storage
+                .get_history(1, Some(AccountType::Disputed), &HistoryQuery::default())
+                .await
+                .expect("get_history should succeed");
+            assert_eq!(tx_ids(&history), vec![hold.id()]);
+
+            let history = storage
+                .get_history(1, Some(AccountType::Chargeback), &HistoryQuery::default())
+                .await
+                .expect("get_history should succeed");
+            assert!(history.is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_store_txs_chained_batch() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+            let other = make_account(2);
+
+            let deposit = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            let split = Transaction::new(
+                vec![make_utxo(deposit.id(), 0, 100.into())],
+                vec![(account, 60.into()), (account, 40.into())],
+                "split-1".into(),
+                Some(2000),
+            )
+            .expect("split should be valid");
+            let payment = Transaction::new(
+                vec![make_utxo(split.id(), 0, 60.into())],
+                vec![(other, 60.into())],
+                "payment-1".into(),
+                Some(2000),
+            )
+            .expect("payment should be valid");
+
+            // Each transaction spends an output created earlier in the same batch
+            storage
+                .store_txs(vec![deposit.clone(), split.clone(), payment.clone()])
+                .await
+                .expect("chained batch should succeed");
+
+            let unspent = storage
+                .get_unspent(&account, None)
+                .await
+                .expect("get_unspent should succeed");
+            assert_eq!(unspent.len(), 1);
+            assert_eq!(unspent[0].id(), (split.id(), 1).into());
+
+            let unspent = storage
+                .get_unspent(&other, None)
+                .await
+                .expect("get_unspent should succeed");
+            assert_eq!(unspent.len(), 1);
+            assert_eq!(unspent[0].id(), (payment.id(), 0).into());
+
+            storage
+                .store_txs(vec![])
+                .await
+                .expect("an empty batch should succeed");
+        }
+
+        #[tokio::test]
+        async fn test_store_txs_failure_rolls_back_batch() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            let deposit = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            storage
+                .store_tx(deposit.clone())
+                .await
+                .expect("deposit should succeed");
+
+            let split = Transaction::new(
+                vec![make_utxo(deposit.id(), 0, 100.into())],
+                vec![(account, 60.into()), (account, 40.into())],
+                "split-1".into(),
+                Some(2000),
+            )
+            .expect("split should be valid");
+            let withdraw = Transaction::new(
+                vec![make_utxo(split.id(), 0, 60.into())],
+                vec![],
+                "deposit-1".into(),
+                Some(2000),
+            )
+            .expect("withdrawal should be valid");
+
+            // The second transaction reuses a reference, the first one must not be committed
+            let result = storage.store_txs(vec![split.clone(), withdraw]).await;
+            assert!(matches!(result, Err(Error::Duplicate)));
+
+            let unspent = storage
+                .get_unspent(&account, None)
+                .await
+                .expect("get_unspent should succeed");
+            assert_eq!(unspent.len(), 1);
+            assert_eq!(unspent[0].id(), (deposit.id(), 0).into());
+            let result = storage
+                .get_tx_by_reference(&account, &"split-1".into())
+                .await
+                .expect("get_tx_by_reference should succeed");
+            assert!(result.is_none());
+            let history = storage
+                .get_history(1, None, &HistoryQuery::default())
+                .await
+                .expect("get_history should succeed");
+            assert_eq!(tx_ids(&history), vec![deposit.id()]);
+            let balance = storage
+                .get_balance(&account)
+                .await
+                .expect("get_balance should succeed");
+            assert_eq!(*balance, 100);
+
+            // The same split can be stored afterwards
+            storage
+                .store_tx(split)
+                .await
+                .expect("split should succeed after the rollback");
+        }
+
+        #[tokio::test]
+        async fn test_get_balance_tracks_stored_txs() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+            let other = make_account(2);
+
+            let balance = storage
+                .get_balance(&account)
+                .await
+                .expect("get_balance should succeed for empty account");
+            assert_eq!(*balance, 0);
+
+            let deposit = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            storage
+                .store_tx(deposit.clone())
+                .await
+                .expect("deposit should succeed");
+            let debt = make_deposit_tx(other, (-30).into(), "debt-1", 1000);
+            storage.store_tx(debt).await.expect("debt should succeed");
+
+            let transfer = Transaction::new(
+                vec![make_utxo(deposit.id(), 0, 100.into())],
+                vec![(other, 70.into()), (account, 30.into())],
+                "transfer-1".into(),
+                Some(2000),
+            )
+            .expect("transfer should be valid");
+            storage
+                .store_tx(transfer.clone())
+                .await
+                .expect("transfer should succeed");
+
+            assert_eq!(*storage.get_balance(&account).await.unwrap(), 30);
+            assert_eq!(*storage.get_balance(&other).await.unwrap(), 40);
+
+            // Spending without outputs takes the funds out of the balance
+            let burn = Transaction::new(
+                vec![make_utxo(transfer.id(), 1, 30.into())],
+                vec![],
+                "burn-1".into(),
+                Some(3000),
+            )
+            .expect("burn should be valid");
+            storage.store_tx(burn).await.expect("burn should succeed");
+
+            assert_eq!(*storage.get_balance(&account).await.unwrap(), 0);
+            assert_eq!(*storage.get_balance(&other).await.unwrap(), 40);
+
+            // A rejected transaction does not move the balance
+            let replay = Transaction::new(
+                vec![make_utxo(transfer.id(), 0, 70.into())],
+                vec![(account, 70.into())],
+                "transfer-1".into(),
+                Some(4000),
+            )
+            .expect("replay should be valid");
+            assert!(matches!(
+                storage.store_tx(replay).await,
+                Err(Error::Duplicate)
+            ));
+            assert_eq!(*storage.get_balance(&account).await.unwrap(), 0);
+            assert_eq!(*storage.get_balance(&other).await.unwrap(), 40);
+        }
+
+        #[tokio::test]
+        async fn test_snapshot_reads_committed_state() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            let deposit = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            storage
+                .store_tx(deposit.clone())
+                .await
+                .expect("deposit should succeed");
+
+            let (balance, unspent, other) = storage
+                .snapshot(move |snapshot| {
+                    Ok((
+                        snapshot.get_balance(&account)?,
+                        snapshot.get_unspent(&account)?,
+                        snapshot.get_unspent(&make_account(2))?,
+                    ))
+                })
+                .await
+                .expect("snapshot should succeed");
+            assert_eq!(*balance, 100);
+            assert_eq!(unspent.len(), 1);
+            assert_eq!(unspent[0].id(), (deposit.id(), 0).into());
+            assert!(other.is_empty());
+
+            // Once the snapshot is done writers go ahead, and new snapshots see their changes
+            let deposit = make_deposit_tx(account, 50.into(), "deposit-2", 2000);
+            storage
+                .store_tx(deposit)
+                .await
+                .expect("deposit should succeed after the snapshot is done");
+
+            let (balance, unspent) = storage
+                .snapshot(move |snapshot| {
+                    Ok((snapshot.get_balance(&account)?, snapshot.get_unspent(&account)?))
+                })
+                .await
+                .expect("snapshot should succeed");
+            assert_eq!(*balance, 150);
+            assert_eq!(unspent.len(), 2);
+        }
+
+        #[tokio::test]
+        async fn test_credit_limit_enforced_on_store() {
+            let storage = $storage_expr;
+            let main = make_account(1);
+            let credit: FullAccount = (1, AccountType::Credit).into();
+
+            let draw = |reference: &str, amount: i128| {
+                Transaction::new(
+                    vec![],
+                    vec![(main, amount.into()), (credit, (-amount).into())],
+                    reference.into(),
+                    Some(1000),
+                )
+                .expect("draw should be valid")
+            };
+
+            // Without a limit the Credit sub-account cannot go negative
+            assert_eq!(*storage.get_credit_limit(&credit).await.unwrap(), 0);
+            assert!(matches!(
+                storage.store_tx(draw("draw-1", 10)).await,
+                Err(Error::CreditLimit(account)) if account == credit
+            ));
+
+            storage
+                .set_credit_limit(&credit, 50.into())
+                .await
+                .expect("set_credit_limit should succeed");
+            assert_eq!(*storage.get_credit_limit(&credit).await.unwrap(), 50);
+            let other: FullAccount = (2, AccountType::Credit).into();
+            assert_eq!(*storage.get_credit_limit(&other).await.unwrap(), 0);
+
+            storage
+                .store_tx(draw("draw-1", 30))
+                .await
+                .expect("draw within the limit should succeed");
+            assert!(matches!(
+                storage.store_tx(draw("draw-2", 30)).await,
+                Err(Error::CreditLimit(account)) if account == credit
+            ));
+            assert_eq!(*storage.get_balance(&credit).await.unwrap(), -30);
+
+            storage
+                .store_tx(draw("draw-2", 20))
+                .await
+                .expect("draw up to the limit should succeed");
+
+            // Lowering the limit blocks draws but not repayments
+            storage
+                .set_credit_limit(&credit, 10.into())
+                .await
+                .expect("set_credit_limit should succeed");
+            let repay = make_deposit_tx(credit, 15.into(), "repay-1", 2000);
+            storage
+                .store_tx(repay)
+                .await
+                .expect("repayment should succeed");
+            assert!(matches!(
+                storage.store_tx(draw("draw-3", 1)).await,
+                Err(Error::CreditLimit(account)) if account == credit
+            ));
+            assert_eq!(*storage.get_balance(&credit).await.unwrap(), -35);
+
+            let limit = storage
+                .snapshot(move |snapshot| snapshot.get_credit_limit(&credit))
+                .await
+                .expect("snapshot should succeed");
+            assert_eq!(*limit, 10);
+        }
+
+        #[tokio::test]
+        async fn test_debt_cannot_be_overpaid() {
+            let storage = $storage_expr;
+            let main = make_account(1);
+            let debt: FullAccount = (1, AccountType::Debt).into();
+            let external: FullAccount = (1, AccountType::External).into();
+
+            let owe = Transaction::new(
+                vec![],
+                vec![(main, 50.into()), (debt, (-50).into())],
+                "owe-1".into(),
+                Some(1000),
+            )
+            .expect("owe should be valid");
+            storage.store_tx(owe).await.expect("owe should succeed");
+
+            let repay = |reference: &str, amount: i128| {
+                Transaction::new(
+                    vec![],
+                    vec![(debt, amount.into()), (external, (-amount).into())],
+                    reference.into(),
+                    Some(2000),
+                )
+                .expect("repayment should be valid")
+            };
+
+            assert!(matches!(
+                storage.store_tx(repay("repay-1", 60)).await,
+                Err(Error::Overpaid(account)) if account == debt
+            ));
+            storage
+                .store_tx(repay("repay-1", 50))
+                .await
+                .expect("repaying what is owed should succeed");
+            assert!(matches!(
+                storage.store_tx(repay("repay-2", 1)).await,
+                Err(Error::Overpaid(account)) if account == debt
+            ));
+            assert_eq!(*storage.get_balance(&debt).await.unwrap(), 0);
+        }
+
+        #[tokio::test]
+        async fn test_get_tx_by_id() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+
+            let mut hold = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            hold.set_expires_at(Some(5000));
+            storage
+                .store_tx(hold.clone())
+                .await
+                .expect("store_tx should succeed");
+
+            let stored = storage
+                .get_tx(&hold.id())
+                .await
+                .expect("get_tx should succeed")
+                .expect("the transaction should exist");
+            assert_eq!(stored.id(), hold.id());
+            assert_eq!(stored.expires_at(), Some(5000));
+
+            let missing = storage
+                .get_tx(&[0u8; 32])
+                .await
+                .expect("get_tx should succeed");
+            assert!(missing.is_none());
+        }
+
+        #[tokio::test]
+        async fn test_store_txs_rejects_double_spend_within_batch() {
+            let storage = $storage_expr;
+            let account = make_account(1);
+            let other = make_account(2);
+
+            let deposit = make_deposit_tx(account, 100.into(), "deposit-1", 1000);
+            let first = Transaction::new(
+                vec![make_utxo(deposit.id(), 0, 100.into())],
+                vec![(other, 100.into())],
+                "spend-1".into(),
+                Some(2000),
+            )
+            .expect("first spend should be valid");
+            let second = Transaction::new(
+                vec![make_utxo(deposit.id(), 0, 100.into())],
+                vec![(account, 100.into())],
+                "spend-2".into(),
+                Some(2000),
+            )
+            .expect("second spend should be valid");
+
+            let result = storage
+                .store_txs(vec![deposit.clone(), first, second])
+                .await;
+            assert!(matches!(result, Err(Error::SpentUtxo(_))));
+
+            // Outputs can only be spent after they are created
+            let spend = Transaction::new(
+                vec![make_utxo(deposit.id(), 0, 100.into())],
+                vec![(other, 100.into())],
+                "spend-1".into(),
+                Some(2000),
+            )
+            .expect("spend should be valid");
+            let result = storage.store_txs(vec![spend, deposit]).await;
+            assert!(matches!(result, Err(Error::MissingUtxo(_))));
+
+            let history = storage
+                .get_history(1, None, &HistoryQuery::default())
+                .await
+                .expect("get_history should succeed");
+            assert!(history.is_empty());
+        }
     };
 }
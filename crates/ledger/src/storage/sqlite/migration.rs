@@ -0,0 +1,864 @@
+//! Versioned schema migrations for the SQLite backend.
+//!
+//! The schema version is kept in the `schema_version` table. Migration `n` upgrades a database
+//! from version `n` to `n + 1`, migrations only ever move forward and are never edited once
+//! released, a schema change is a new migration appended to [`MIGRATIONS`].
+use rusqlite::{Connection, TransactionBehavior, params, types::Type};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{OpenError, OptionalExt, Sqlite};
+use crate::storage::BoxError;
+use crate::{AccountType, Reference, ReferenceKind as Kind, Transaction, transaction};
+
+/// A forward migration, run inside the transaction that records the new version.
+pub(super) type Migration = fn(&rusqlite::Transaction<'_>) -> rusqlite::Result<()>;
+
+/// Every migration, in order. The latest schema version is the length of this list.
+pub(super) const MIGRATIONS: &[Migration] = &[
+    initial_schema,
+    reference_kinds,
+    assets,
+    account_history,
+    running_balances,
+    external_counter_entries,
+];
+
+/// Brings the database up to the schema of `migrations`.
+///
+/// Pending migrations and the version bump run in a single immediate transaction, so a failed
+/// migration leaves the database untouched and two processes opening the same file cannot
+/// migrate it twice. Databases with a version newer than `migrations` are refused.
+pub(super) fn migrate(conn: &mut Connection, migrations: &[Migration]) -> Result<(), OpenError> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    tx.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")?;
+    let found: u32 = tx
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?
+        .unwrap_or_default();
+    let supported = u32::try_from(migrations.len()).expect("too many migrations");
+
+    if found > supported {
+        return Err(OpenError::UnsupportedVersion { found, supported });
+    }
+    if found == supported {
+        return Ok(());
+    }
+
+    for migration in &migrations[found as usize..] {
+        migration(&tx)?;
+    }
+
+    tx.execute("DELETE FROM schema_version", [])?;
+    tx.execute(
+        "INSERT INTO schema_version (version) VALUES (?)",
+        [supported],
+    )?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Version 1, the schema as it was when versioning was introduced.
+///
+/// Files created before then already have these tables and are adopted as version 1.
+fn initial_schema(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS transactions (
+            tx_id BLOB PRIMARY KEY,
+            tx_data BLOB NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS utxos (
+            hash_id BLOB NOT NULL,
+            pos INTEGER NOT NULL,
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            amount INTEGER NOT NULL,
+            spent_at BLOB,
+            PRIMARY KEY (hash_id, pos)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_utxos_account
+            ON utxos (account_id, account_type);
+
+        CREATE TABLE IF NOT EXISTS tx_references (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            reference TEXT NOT NULL,
+            tx_id BLOB NOT NULL,
+            PRIMARY KEY (account_id, account_type, reference)
+        );
+
+        CREATE TABLE IF NOT EXISTS accounts (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            PRIMARY KEY (account_id, account_type)
+        );
+        ",
+    )
+}
+
+/// Version 2, references carry their kind next to the external id.
+///
+/// Internal references used to be the client reference with a prefix. Only transactions with
+/// both inputs and outputs were internal (client deposits have no inputs, withdrawals no
+/// outputs), so a prefix is read as a kind only on those, a client reference that happens to
+/// look like an internal one stays a client reference. The references stored inside the
+/// transactions are rewritten too, the transactions stay under the ID they were stored with.
+///
+/// References used to be taken by the receiving accounts only, they are now taken by the
+/// spending accounts as well. Where the backfill clashes with an existing reference the
+/// oldest transaction keeps it.
+fn reference_kinds(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        DROP TABLE tx_references;
+
+        CREATE TABLE tx_references (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            reference_kind INTEGER NOT NULL,
+            reference TEXT NOT NULL,
+            tx_id BLOB NOT NULL,
+            PRIMARY KEY (account_id, account_type, reference_kind, reference)
+        );
+        ",
+    )?;
+
+    for (tx_id, tx_data) in transactions(tx)? {
+        let mut data: serde_json::Value = serde_json::from_slice(&tx_data).map_err(invalid_data)?;
+        let is_internal = ["from", "to"]
+            .iter()
+            .all(|side| data[side].as_array().is_some_and(|items| !items.is_empty()));
+        let reference = data["reference"]
+            .as_str()
+            .ok_or_else(|| invalid_data("reference is not a string"))?;
+        let reference = typed_reference(reference, is_internal);
+
+        data["reference"] = serde_json::to_value(&reference).map_err(invalid_data)?;
+        tx.execute(
+            "UPDATE transactions SET tx_data = ? WHERE tx_id = ?",
+            params![serde_json::to_vec(&data).map_err(invalid_data)?, tx_id],
+        )?;
+
+        tx.execute(
+            "INSERT OR IGNORE INTO tx_references
+                 (account_id, account_type, reference_kind, reference, tx_id)
+                 SELECT DISTINCT account_id, account_type, ?1, ?2, ?3 FROM utxos
+                 WHERE hash_id = ?3 OR spent_at = ?3",
+            params![reference.kind().to_byte() as i64, reference.id(), tx_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads a reference stored before references had a kind.
+fn typed_reference(reference: &str, is_internal: bool) -> Reference {
+    const PREFIXES: [(&str, Kind); 4] = [
+        ("dispute:", Kind::Dispute),
+        ("resolved:", Kind::Resolve),
+        ("chargeback:", Kind::Chargeback),
+        ("Exchange for ", Kind::Change),
+    ];
+
+    if is_internal {
+        for (prefix, kind) in PREFIXES {
+            if let Some(id) = reference.strip_prefix(prefix) {
+                return Reference::new(kind, id.to_owned());
+            }
+        }
+    }
+
+    reference.into()
+}
+
+/// Version 3, every account is denominated in an asset and amounts are stored as blobs.
+///
+/// Existing accounts belong to the default asset. Amounts move from SQLite integers to the
+/// 16 byte encoding of `Sqlite::amount_to_blob`, UTXOs are copied in their original order so
+/// coin selection keeps picking the oldest first.
+fn assets(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        ALTER TABLE utxos RENAME TO utxos_v2;
+        DROP INDEX idx_utxos_account;
+
+        CREATE TABLE utxos (
+            hash_id BLOB NOT NULL,
+            pos INTEGER NOT NULL,
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            asset INTEGER NOT NULL,
+            amount BLOB NOT NULL,
+            spent_at BLOB,
+            PRIMARY KEY (hash_id, pos)
+        );
+
+        CREATE INDEX idx_utxos_account
+            ON utxos (account_id, account_type, asset);
+
+        ALTER TABLE tx_references RENAME TO tx_references_v2;
+
+        CREATE TABLE tx_references (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            asset INTEGER NOT NULL,
+            reference_kind INTEGER NOT NULL,
+            reference TEXT NOT NULL,
+            tx_id BLOB NOT NULL,
+            PRIMARY KEY (account_id, account_type, asset, reference_kind, reference)
+        );
+
+        INSERT INTO tx_references
+            SELECT account_id, account_type, 0, reference_kind, reference, tx_id
+            FROM tx_references_v2;
+
+        DROP TABLE tx_references_v2;
+
+        ALTER TABLE accounts RENAME TO accounts_v2;
+
+        CREATE TABLE accounts (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            asset INTEGER NOT NULL,
+            PRIMARY KEY (account_id, account_type, asset)
+        );
+
+        INSERT INTO accounts SELECT account_id, account_type, 0 FROM accounts_v2;
+
+        DROP TABLE accounts_v2;
+        ",
+    )?;
+
+    {
+        let mut select = tx.prepare(
+            "SELECT hash_id, pos, account_id, account_type, amount, spent_at FROM utxos_v2
+             ORDER BY rowid",
+        )?;
+        let mut insert = tx.prepare(
+            "INSERT INTO utxos (hash_id, pos, account_id, account_type, asset, amount, spent_at)
+             VALUES (?, ?, ?, ?, 0, ?, ?)",
+        )?;
+
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let amount: i64 = row.get(4)?;
+            insert.execute(params![
+                row.get::<_, Vec<u8>>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+                Sqlite::amount_to_blob(amount.into()),
+                row.get::<_, Option<Vec<u8>>>(5)?,
+            ])?;
+        }
+    }
+
+    tx.execute_batch("DROP TABLE utxos_v2")
+}
+
+/// Version 4, the transactions of each account are indexed by time for its history.
+fn account_history(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE account_txs (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            asset INTEGER NOT NULL,
+            timestamp INTEGER NOT NULL,
+            tx_id BLOB NOT NULL,
+            PRIMARY KEY (account_id, account_type, asset, tx_id)
+        );
+
+        CREATE INDEX idx_account_txs_history
+            ON account_txs (account_id, timestamp, tx_id);
+        ",
+    )?;
+
+    #[derive(serde::Deserialize)]
+    struct Stored {
+        timestamp: u64,
+    }
+
+    // Every account a transaction paid or was paid by took part in it
+    for (tx_id, tx_data) in transactions(tx)? {
+        let stored: Stored = serde_json::from_slice(&tx_data).map_err(invalid_data)?;
+        tx.execute(
+            "INSERT INTO account_txs (account_id, account_type, asset, timestamp, tx_id)
+                 SELECT DISTINCT account_id, account_type, asset, ?1, ?2 FROM utxos
+                 WHERE hash_id = ?2 OR spent_at = ?2",
+            params![Sqlite::timestamp_to_int(stored.timestamp), tx_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Version 5, running balances and credit limits.
+///
+/// Balances are backfilled with the sum of each account's unspent outputs.
+fn running_balances(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE balances (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            asset INTEGER NOT NULL,
+            balance BLOB NOT NULL,
+            PRIMARY KEY (account_id, account_type, asset)
+        );
+
+        CREATE TABLE credit_limits (
+            account_id INTEGER NOT NULL,
+            account_type INTEGER NOT NULL,
+            asset INTEGER NOT NULL,
+            credit_limit BLOB NOT NULL,
+            PRIMARY KEY (account_id, account_type, asset)
+        );
+        ",
+    )?;
+
+    let mut balances = BTreeMap::<(i64, i64, i64), i128>::new();
+    {
+        let mut select = tx.prepare(
+            "SELECT account_id, account_type, asset, amount FROM utxos WHERE spent_at IS NULL",
+        )?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let amount: Vec<u8> = row.get(3)?;
+            let amount =
+                Sqlite::blob_to_amount(&amount, || "utxos".to_owned()).map_err(invalid_data)?;
+            let balance = balances
+                .entry((row.get(0)?, row.get(1)?, row.get(2)?))
+                .or_default();
+            *balance = balance
+                .checked_add(amount)
+                .ok_or_else(|| invalid_data("balance overflow"))?;
+        }
+    }
+
+    for ((account_id, account_type, asset), balance) in balances {
+        tx.execute(
+            "INSERT INTO balances (account_id, account_type, asset, balance) VALUES (?, ?, ?, ?)",
+            params![
+                account_id,
+                account_type,
+                asset,
+                Sqlite::amount_to_blob(balance)
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Version 6, deposits and withdrawals stored before the External sub-account existed get
+/// their counter-entries.
+///
+/// They were one-sided, deposits had no inputs and withdrawals no outputs, so the balances
+/// of the ledger no longer add up to zero. Every account gets a single System transaction
+/// moving the net amount of its one-sided transactions into its External sub-account, as if
+/// they had been stored with their External legs. Transactions already stored with them
+/// balance on their own and are left out.
+fn external_counter_entries(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+    let ids = |sql: &str| -> rusqlite::Result<BTreeSet<Vec<u8>>> {
+        tx.prepare(sql)?.query_map([], |row| row.get(0))?.collect()
+    };
+    let created = ids("SELECT DISTINCT hash_id FROM utxos")?;
+    let spending = ids("SELECT DISTINCT spent_at FROM utxos WHERE spent_at IS NOT NULL")?;
+
+    // What one-sided transactions added to each account, by (account_id, asset)
+    let mut flows = BTreeMap::<(i64, i64), i128>::new();
+    {
+        let mut select =
+            tx.prepare("SELECT hash_id, account_id, asset, amount, spent_at FROM utxos")?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let hash_id: Vec<u8> = row.get(0)?;
+            let spent_at: Option<Vec<u8>> = row.get(4)?;
+            let amount: Vec<u8> = row.get(3)?;
+            let amount =
+                Sqlite::blob_to_amount(&amount, || "utxos".to_owned()).map_err(invalid_data)?;
+
+            // Created by a transaction without inputs, or spent by one without outputs
+            let mut flow = 0i128;
+            if !spending.contains(&hash_id) {
+                flow = amount;
+            }
+            if spent_at.is_some_and(|spent_at| !created.contains(&spent_at)) {
+                flow = flow
+                    .checked_sub(amount)
+                    .ok_or_else(|| invalid_data("flow overflow"))?;
+            }
+
+            let total = flows.entry((row.get(1)?, row.get(2)?)).or_default();
+            *total = total
+                .checked_add(flow)
+                .ok_or_else(|| invalid_data("flow overflow"))?;
+        }
+    }
+
+    let reference = Reference::new(Kind::System, "external-counter-entries".to_owned());
+    let account_type = Sqlite::account_type_to_int(AccountType::External);
+    let timestamp = transaction::now();
+    for ((account_id, asset), flow) in flows {
+        if flow == 0 {
+            continue;
+        }
+
+        let key = || format!("balances ({account_id}, {account_type}, {asset})");
+        let account =
+            Sqlite::int_to_account((account_id, account_type, asset), key).map_err(invalid_data)?;
+        let amount = flow
+            .checked_neg()
+            .ok_or_else(|| invalid_data("flow overflow"))?;
+        let entry = Transaction::new(
+            vec![],
+            vec![(account, amount.into())],
+            reference.clone(),
+            Some(timestamp),
+        )
+        .map_err(invalid_data)?;
+        let tx_id = entry.id();
+
+        let balance = tx
+            .query_row(
+                "SELECT balance FROM balances
+                 WHERE account_id = ? AND account_type = ? AND asset = ?",
+                params![account_id, account_type, asset],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?
+            .map(|balance| Sqlite::blob_to_amount(&balance, key))
+            .transpose()
+            .map_err(invalid_data)?
+            .unwrap_or_default()
+            .checked_add(amount)
+            .ok_or_else(|| invalid_data("balance overflow"))?;
+
+        tx.execute(
+            "INSERT INTO transactions (tx_id, tx_data) VALUES (?, ?)",
+            params![
+                tx_id.as_slice(),
+                serde_json::to_vec(&entry).map_err(invalid_data)?
+            ],
+        )?;
+        tx.execute(
+            "INSERT INTO utxos (hash_id, pos, account_id, account_type, asset, amount, spent_at)
+             VALUES (?, 0, ?, ?, ?, ?, NULL)",
+            params![
+                tx_id.as_slice(),
+                account_id,
+                account_type,
+                asset,
+                Sqlite::amount_to_blob(amount)
+            ],
+        )?;
+        tx.execute(
+            "INSERT INTO balances (account_id, account_type, asset, balance) VALUES (?, ?, ?, ?)
+             ON CONFLICT (account_id, account_type, asset)
+             DO UPDATE SET balance = excluded.balance",
+            params![
+                account_id,
+                account_type,
+                asset,
+                Sqlite::amount_to_blob(balance)
+            ],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO accounts (account_id, account_type, asset) VALUES (?, ?, ?)",
+            params![account_id, account_type, asset],
+        )?;
+        tx.execute(
+            "INSERT INTO tx_references
+                 (account_id, account_type, asset, reference_kind, reference, tx_id)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                account_id,
+                account_type,
+                asset,
+                reference.kind().to_byte() as i64,
+                reference.id(),
+                tx_id.as_slice()
+            ],
+        )?;
+        tx.execute(
+            "INSERT INTO account_txs (account_id, account_type, asset, timestamp, tx_id)
+             VALUES (?, ?, ?, ?, ?)",
+            params![
+                account_id,
+                account_type,
+                asset,
+                Sqlite::timestamp_to_int(timestamp),
+                tx_id.as_slice()
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Every stored transaction as `(tx_id, tx_data)`, in the order they were stored.
+fn transactions(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    tx.prepare("SELECT tx_id, tx_data FROM transactions ORDER BY rowid")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+/// Reports data a migration cannot convert, the migration is rolled back.
+fn invalid_data(err: impl Into<BoxError>) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, err.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::sqlite::Sqlite;
+    use crate::storage::sqlite::tests::TempFile;
+    use crate::storage::{self, HistoryQuery};
+    use crate::{DEFAULT_ASSET, Error, Ledger, Storage};
+    use sha2::{Digest, Sha256};
+
+    fn version(conn: &Connection) -> u32 {
+        conn.query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+            .expect("version should be recorded")
+    }
+
+    fn has_column(conn: &Connection, table: &str, column: &str) -> bool {
+        conn.prepare(&format!("SELECT {column} FROM {table}"))
+            .is_ok()
+    }
+
+    fn add_created_at(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        tx.execute_batch("ALTER TABLE utxos ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0")
+    }
+
+    fn broken(tx: &rusqlite::Transaction<'_>) -> rusqlite::Result<()> {
+        tx.execute_batch("ALTER TABLE missing ADD COLUMN nothing INTEGER")
+    }
+
+    #[test]
+    fn new_database_is_at_latest_version() {
+        let mut conn = Connection::open_in_memory().expect("open should succeed");
+        migrate(&mut conn, MIGRATIONS).expect("migrate should succeed");
+        assert_eq!(version(&conn) as usize, MIGRATIONS.len());
+
+        // Reopening is a no-op
+        migrate(&mut conn, MIGRATIONS).expect("migrate should succeed");
+        assert_eq!(version(&conn) as usize, MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn upgrades_old_file_and_keeps_data() {
+        let file = TempFile::new("migrate-upgrade");
+
+        let deposit = Transaction::new(
+            vec![],
+            vec![(1.into(), 100.into())],
+            "deposit-1".into(),
+            Some(1000),
+        )
+        .expect("deposit should be valid");
+        let storage = Sqlite::open(file.path(), 1).expect("open should succeed");
+        storage
+            .store_tx(deposit)
+            .await
+            .expect("deposit should succeed");
+        drop(storage);
+
+        let upgraded = [MIGRATIONS, &[add_created_at]].concat();
+        let mut conn = Connection::open(file.path()).expect("open should succeed");
+        assert!(!has_column(&conn, "utxos", "created_at"));
+        migrate(&mut conn, &upgraded).expect("migrate should succeed");
+
+        assert_eq!(version(&conn) as usize, upgraded.len());
+        let (count, created_at): (u32, i64) = conn
+            .query_row("SELECT COUNT(*), MAX(created_at) FROM utxos", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .expect("utxos should be readable");
+        assert_eq!((count, created_at), (1, 0));
+        drop(conn);
+
+        // This build only knows the older schema
+        assert!(matches!(
+            Sqlite::open(file.path(), 1),
+            Err(OpenError::UnsupportedVersion { found, supported })
+                if found as usize == upgraded.len() && supported as usize == MIGRATIONS.len()
+        ));
+    }
+
+    /// Hashes a transaction the way it was hashed before references had a kind, the reference
+    /// went in as a plain string.
+    fn baseline_id(
+        inputs: &[([u8; 32], u8)],
+        outputs: &[(u16, AccountType, i128)],
+        timestamp: u64,
+        reference: &str,
+    ) -> [u8; 32] {
+        let mut inputs_hasher = Sha256::new();
+        for (hash_id, pos) in inputs {
+            inputs_hasher.update(hash_id);
+            inputs_hasher.update([*pos]);
+        }
+
+        let mut outputs_hasher = Sha256::new();
+        for (account_id, account_type, amount) in outputs {
+            outputs_hasher.update(account_id.to_le_bytes());
+            outputs_hasher.update([account_type.to_byte()]);
+            outputs_hasher.update(amount.to_le_bytes());
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(inputs_hasher.finalize());
+        hasher.update(outputs_hasher.finalize());
+        hasher.update(timestamp.to_le_bytes());
+        hasher.update(reference.as_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Writes rows the way the storage did before schema versioning, references were plain
+    /// strings with a prefix for internal ones.
+    fn baseline_file(path: &str) {
+        let mut conn = Connection::open(path).expect("open should succeed");
+        let tx = conn.transaction().expect("transaction should start");
+        initial_schema(&tx).expect("baseline schema should be created");
+
+        let deposit_1 = baseline_id(&[], &[(1, AccountType::Main, 100)], 1000, "deposit-1");
+        // A client reference that looks like an internal one
+        let deposit_2 = baseline_id(
+            &[],
+            &[(2, AccountType::Main, 50)],
+            1000,
+            "dispute:deposit-1",
+        );
+        let dispute = baseline_id(
+            &[(deposit_1, 0)],
+            &[(1, AccountType::Disputed, 100)],
+            2000,
+            "dispute:deposit-1",
+        );
+        let withdrawal = baseline_id(&[(deposit_2, 0)], &[], 3000, "withdraw-1");
+
+        let deposit = |account: u16, amount: i64, reference: &str| {
+            serde_json::json!({
+                "from": [],
+                "to": [[[account, "Main"], amount]],
+                "reference": reference,
+                "timestamp": 1000,
+            })
+        };
+        let utxo = |id: [u8; 32], amount: i64| serde_json::json!({"id": {"id": id.to_vec(), "pos": 0}, "amount": amount});
+        let transactions = [
+            (deposit_1, deposit(1, 100, "deposit-1")),
+            (deposit_2, deposit(2, 50, "dispute:deposit-1")),
+            (
+                dispute,
+                serde_json::json!({
+                    "from": [utxo(deposit_1, 100)],
+                    "to": [[[1, "Disputed"], 100]],
+                    "reference": "dispute:deposit-1",
+                    "timestamp": 2000,
+                }),
+            ),
+            (
+                withdrawal,
+                serde_json::json!({
+                    "from": [utxo(deposit_2, 50)],
+                    "to": [],
+                    "reference": "withdraw-1",
+                    "timestamp": 3000,
+                }),
+            ),
+        ];
+        for (tx_id, data) in &transactions {
+            tx.execute(
+                "INSERT INTO transactions (tx_id, tx_data) VALUES (?, ?)",
+                params![
+                    tx_id.as_slice(),
+                    serde_json::to_vec(data).expect("valid json")
+                ],
+            )
+            .expect("insert should succeed");
+        }
+
+        let utxos = [
+            (deposit_1, 1, 0, 100, Some(dispute)),
+            (deposit_2, 2, 0, 50, Some(withdrawal)),
+            (dispute, 1, 1, 100, None),
+        ];
+        for (hash_id, account_id, account_type, amount, spent_at) in utxos {
+            tx.execute(
+                "INSERT INTO utxos (hash_id, pos, account_id, account_type, amount, spent_at)
+                 VALUES (?, 0, ?, ?, ?, ?)",
+                params![
+                    hash_id.as_slice(),
+                    account_id,
+                    account_type,
+                    amount,
+                    spent_at.as_ref().map(|id| id.as_slice())
+                ],
+            )
+            .expect("insert should succeed");
+            tx.execute(
+                "INSERT OR IGNORE INTO accounts (account_id, account_type) VALUES (?, ?)",
+                params![account_id, account_type],
+            )
+            .expect("insert should succeed");
+        }
+
+        let references = [
+            (1, 0, "deposit-1", deposit_1),
+            (2, 0, "dispute:deposit-1", deposit_2),
+            (1, 1, "dispute:deposit-1", dispute),
+        ];
+        for (account_id, account_type, reference, tx_id) in references {
+            tx.execute(
+                "INSERT INTO tx_references (account_id, account_type, reference, tx_id)
+                 VALUES (?, ?, ?, ?)",
+                params![account_id, account_type, reference, tx_id.as_slice()],
+            )
+            .expect("insert should succeed");
+        }
+
+        tx.commit().expect("commit should succeed");
+    }
+
+    #[tokio::test]
+    async fn upgrades_baseline_file() {
+        let file = TempFile::new("migrate-baseline");
+        baseline_file(file.path());
+
+        let ledger = Ledger::new(Sqlite::open(file.path(), 1).expect("open should succeed"));
+        let conn = Connection::open(file.path()).expect("open should succeed");
+        assert_eq!(version(&conn) as usize, MIGRATIONS.len());
+        assert!(has_column(&conn, "utxos", "asset"));
+
+        // Rewriting the references keeps every transaction under the ID it hashes to
+        let mut select = conn
+            .prepare("SELECT tx_id, tx_data FROM transactions")
+            .expect("transactions should be readable");
+        let stored = select
+            .query_map([], |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .expect("transactions should be readable")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("transactions should be readable");
+        // The four baseline transactions and the counter-entry of account 1, account 2 withdrew
+        // all it deposited
+        assert_eq!(stored.len(), 5);
+        for (tx_id, tx_data) in stored {
+            let tx: Transaction = serde_json::from_slice(&tx_data).expect("valid transaction");
+            assert_eq!(tx_id, tx.id());
+        }
+        drop(select);
+        drop(conn);
+
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!((*balances.available, *balances.disputed), (0, 100));
+        assert!(ledger.verify_balances().await.expect("verify").is_empty());
+
+        // The net of the one-sided deposits and withdrawals moved to the External sub-accounts
+        let trial_balance = ledger
+            .trial_balance()
+            .await
+            .expect("the migrated ledger should balance");
+        let external = |account: u16| {
+            trial_balance
+                .accounts
+                .iter()
+                .find(|(full, _)| *full == (account, AccountType::External).into())
+                .map(|(_, balance)| **balance)
+        };
+        assert_eq!((external(1), external(2)), (Some(-100), None));
+
+        // Prefixes become kinds, but only on internal transactions
+        let storage = &ledger.storage;
+        let dispute = storage
+            .get_tx_by_reference(
+                &(1, AccountType::Disputed).into(),
+                &Reference::new(Kind::Dispute, "deposit-1".to_owned()),
+            )
+            .await
+            .expect("lookup should succeed")
+            .expect("dispute should be found");
+        assert_eq!(dispute.reference().kind(), Kind::Dispute);
+        assert!(
+            storage
+                .get_tx_by_reference(&2.into(), &"dispute:deposit-1".into())
+                .await
+                .expect("lookup should succeed")
+                .is_some()
+        );
+
+        // The spending side now holds the reference too, and replaying the withdrawal finds it
+        assert!(matches!(
+            ledger.deposit(2, "withdraw-1", 10.into()).await,
+            Err(Error::Storage(storage::Error::Duplicate))
+        ));
+        let withdrawal = baseline_id(
+            &[(
+                baseline_id(
+                    &[],
+                    &[(2, AccountType::Main, 50)],
+                    1000,
+                    "dispute:deposit-1",
+                ),
+                0,
+            )],
+            &[],
+            3000,
+            "withdraw-1",
+        );
+        assert_eq!(
+            ledger
+                .withdraw(2, "withdraw-1", 50.into())
+                .await
+                .expect("the withdrawal should be replayed"),
+            withdrawal
+        );
+
+        let history = ledger
+            .get_history(1, None, HistoryQuery::default())
+            .await
+            .expect("get_history should succeed");
+        // The deposit, the dispute and the counter-entry
+        assert_eq!(history.transactions.len(), 3);
+
+        ledger
+            .resolve(1, "deposit-1")
+            .await
+            .expect("the dispute should be resolved");
+        ledger
+            .deposit(1, "deposit-2", 5.into())
+            .await
+            .expect("deposit should succeed");
+        let balances = ledger.get_balances(1).await.expect("get_balances")[&DEFAULT_ASSET];
+        assert_eq!((*balances.available, *balances.disputed), (105, 0));
+        assert!(ledger.verify_balances().await.expect("verify").is_empty());
+        ledger
+            .trial_balance()
+            .await
+            .expect("the ledger should still balance");
+    }
+
+    #[test]
+    fn failed_migration_changes_nothing() {
+        let file = TempFile::new("migrate-failed");
+        drop(Sqlite::open(file.path(), 1).expect("open should succeed"));
+
+        let mut conn = Connection::open(file.path()).expect("open should succeed");
+        let upgraded = [MIGRATIONS, &[add_created_at, broken]].concat();
+        assert!(matches!(
+            migrate(&mut conn, &upgraded),
+            Err(OpenError::Sqlite(_))
+        ));
+
+        assert_eq!(version(&conn) as usize, MIGRATIONS.len());
+        assert!(!has_column(&conn, "utxos", "created_at"));
+    }
+}
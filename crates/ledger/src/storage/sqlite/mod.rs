@@ -0,0 +1,1314 @@
+//! SQLite implementation of the Storage trait.
+use crate::transaction::{HashId, Transaction, Utxo};
+use crate::{AccountId, AccountType, Amount, AssetId, FullAccount, Reference};
+
+use futures::Stream;
+use rusqlite::{Connection, ErrorCode, TransactionBehavior, params};
+use std::collections::{BTreeSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use super::{BoxError, Error, HistoryQuery, Order, Snapshot, Storage, UtxoOrder};
+use pool::Pool;
+
+mod migration;
+mod pool;
+
+/// Error opening a SQLite database.
+#[derive(Debug, thiserror::Error)]
+pub enum OpenError {
+    /// The database could not be opened or migrated.
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    /// The database was written by a newer version, with a schema this build does not know.
+    #[error("Schema version {found} is newer than the supported version {supported}")]
+    UnsupportedVersion {
+        /// Schema version of the database.
+        found: u32,
+        /// Latest schema version this build knows.
+        supported: u32,
+    },
+}
+
+/// How long a write waits for another connection to release the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// SQLite-backed storage implementation.
+///
+/// Uses an in-memory SQLite database by default, but can be configured to use a file-based
+/// database for persistence.
+///
+/// Database work runs on tokio's blocking thread pool, never on the async workers. File-backed
+/// databases run in WAL mode: writes go through a single connection and reads through a pool of
+/// read connections, readers wait neither for the writer nor for each other. An in-memory
+/// database cannot be shared between connections, reads and writes take turns on its only one.
+pub struct Sqlite {
+    writer: Arc<Pool>,
+    readers: Arc<Pool>,
+}
+
+impl Default for Sqlite {
+    fn default() -> Self {
+        Self::in_memory().expect("failed to create in-memory SQLite database")
+    }
+}
+
+impl Sqlite {
+    /// Creates a new in-memory SQLite storage.
+    pub fn in_memory() -> Result<Self, OpenError> {
+        let writer = Self::writer(Connection::open_in_memory()?)?;
+        Ok(Self {
+            readers: writer.clone(),
+            writer,
+        })
+    }
+
+    /// Creates a new file-backed SQLite storage, with `readers` connections serving reads.
+    ///
+    /// Pending schema migrations are applied on open, and databases written by a newer version
+    /// are refused. Without readers, reads share the writer connection.
+    pub fn open(path: &str, readers: usize) -> Result<Self, OpenError> {
+        let conn = Connection::open(path)?;
+        // Readers see the last commit without waiting for the writer, and do not hold it back.
+        // The mode is kept in the file, every later connection uses it too
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        let writer = Self::writer(conn)?;
+
+        if readers == 0 {
+            return Ok(Self {
+                readers: writer.clone(),
+                writer,
+            });
+        }
+
+        let readers = (0..readers)
+            .map(|_| {
+                let conn = Connection::open(path)?;
+                conn.busy_timeout(BUSY_TIMEOUT)?;
+                conn.pragma_update(None, "query_only", true)?;
+                Ok(conn)
+            })
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        Ok(Self {
+            readers: Pool::new(readers),
+            writer,
+        })
+    }
+
+    fn writer(mut conn: Connection) -> Result<Arc<Pool>, OpenError> {
+        // Other processes may hold the write lock of a file-backed database, wait for them
+        // instead of failing right away
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+
+        migration::migrate(&mut conn, migration::MIGRATIONS)?;
+
+        Ok(Pool::new(vec![conn]))
+    }
+
+    /// Runs database work on the blocking thread pool, with a connection of `pool` to itself.
+    ///
+    /// The connection goes back to the pool once the work is done, even if the caller stopped
+    /// waiting for it.
+    async fn run<T, F>(pool: &Arc<Pool>, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let mut conn = pool.get().await?;
+        tokio::task::spawn_blocking(move || work(&mut conn))
+            .await
+            .map_err(|err| Error::Backend(Box::new(err)))?
+    }
+
+    async fn read<T, F>(&self, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        Self::run(&self.readers, work).await
+    }
+
+    async fn write<T, F>(&self, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        Self::run(&self.writer, work).await
+    }
+
+    fn account_type_to_int(typ: crate::account::Type) -> i64 {
+        typ.to_byte() as i64
+    }
+
+    /// SQLite integers are signed, timestamps past `i64::MAX` saturate instead of wrapping.
+    fn timestamp_to_int(timestamp: u64) -> i64 {
+        timestamp.min(i64::MAX as u64) as i64
+    }
+
+    /// Amounts are i128, wider than SQLite integers. They are stored as 16 big-endian bytes
+    /// with the sign bit flipped, so comparing the blobs orders them like the amounts.
+    fn amount_to_blob(amount: i128) -> [u8; 16] {
+        ((amount as u128) ^ (1 << 127)).to_be_bytes()
+    }
+
+    /// Inverse of `amount_to_blob`.
+    ///
+    /// Stored values are decoded with the `key` of the row they were read from, so a corrupt
+    /// row is reported instead of read as something else.
+    fn blob_to_amount(blob: &[u8], key: impl FnOnce() -> String) -> Result<i128, Error> {
+        let blob: [u8; 16] = blob.try_into().map_err(|err| Self::corrupt(key(), err))?;
+        Ok((u128::from_be_bytes(blob) ^ (1 << 127)) as i128)
+    }
+
+    fn int_to_account_type(
+        val: i64,
+        key: impl FnOnce() -> String,
+    ) -> Result<crate::account::Type, Error> {
+        Ok(match val {
+            0 => crate::account::Type::Main,
+            1 => crate::account::Type::Disputed,
+            2 => crate::account::Type::Chargeback,
+            3 => crate::account::Type::Lock,
+            4 => crate::account::Type::Debt,
+            5 => crate::account::Type::External,
+            6 => crate::account::Type::Credit,
+            7 => crate::account::Type::Pending,
+            _ => return Err(Self::corrupt(key(), format!("unknown account type {val}"))),
+        })
+    }
+
+    fn int_to_account(
+        (id, typ, asset): (i64, i64, i64),
+        key: impl Fn() -> String,
+    ) -> Result<FullAccount, Error> {
+        Ok((
+            AccountId::try_from(id).map_err(|err| Self::corrupt(key(), err))?,
+            Self::int_to_account_type(typ, &key)?,
+            AssetId::try_from(asset).map_err(|err| Self::corrupt(key(), err))?,
+        )
+            .into())
+    }
+
+    fn row_to_utxo(hash_id: &[u8], pos: i64, amount: &[u8], asset: AssetId) -> Result<Utxo, Error> {
+        let key = || format!("utxos ({}, {pos})", Self::hex(hash_id));
+        let hash_id: HashId = hash_id
+            .try_into()
+            .map_err(|err| Self::corrupt(key(), err))?;
+        let pos = u8::try_from(pos).map_err(|err| Self::corrupt(key(), err))?;
+        let amount = Self::blob_to_amount(amount, key)?;
+        Ok(Utxo::new((hash_id, pos).into(), amount.into(), asset))
+    }
+
+    fn data_to_tx(tx_id: &[u8], tx_data: &[u8]) -> Result<Transaction, Error> {
+        serde_json::from_slice(tx_data)
+            .map_err(|err| Self::corrupt(format!("transactions ({})", Self::hex(tx_id)), err))
+    }
+
+    fn corrupt(key: String, source: impl Into<BoxError>) -> Error {
+        Error::Corrupt {
+            key,
+            source: source.into(),
+        }
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    /// Maps a failed insert, a taken unique key means the row was already there.
+    fn insert_error(err: rusqlite::Error) -> Error {
+        match &err {
+            rusqlite::Error::SqliteFailure(failure, _)
+                if failure.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                    || failure.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                Error::Duplicate
+            }
+            _ => err.into(),
+        }
+    }
+
+    /// Validates and stores a single transaction inside an open SQL transaction.
+    ///
+    /// The checks up front give precise errors, the schema enforces the same rules in case
+    /// another connection got in between: IDs and references are unique keys, and a UTXO is
+    /// only marked as spent if it is still unspent.
+    fn store_one(conn: &Connection, tx: &Transaction) -> Result<(), Error> {
+        let tx_id = tx.id();
+        let tx_id_bytes = tx_id.as_slice();
+
+        // Check for duplicate transaction
+        let exists: bool = conn
+            .query_row(
+                "SELECT 1 FROM transactions WHERE tx_id = ?",
+                params![tx_id_bytes],
+                |_| Ok(true),
+            )
+            .optional()?
+            .unwrap_or(false);
+
+        if exists {
+            return Err(Error::Duplicate);
+        }
+
+        // Every account that spends or receives funds in this tx
+        let mut accounts = BTreeSet::new();
+        // What each input takes away from its account balance
+        let mut debits = Vec::new();
+
+        // Verify all input UTXOs exist and are unspent, earlier transactions of the batch are
+        // already visible
+        let mut spent = BTreeSet::new();
+        for input in tx.inputs() {
+            let utxo_id = input.id();
+            if !spent.insert(utxo_id) {
+                return Err(Error::SpentUtxo(utxo_id));
+            }
+
+            let (hash_id, pos) = (utxo_id.hash_id(), utxo_id.pos());
+
+            // (amount, spent, owner)
+            let utxo_info = conn
+                .query_row(
+                    "SELECT amount, spent_at, account_id, account_type, asset FROM utxos
+                     WHERE hash_id = ? AND pos = ?",
+                    params![hash_id.as_slice(), pos as i64],
+                    |row| {
+                        Ok((
+                            row.get::<_, Vec<u8>>(0)?,
+                            row.get::<_, Option<Vec<u8>>>(1)?.is_some(),
+                            (row.get(2)?, row.get(3)?, row.get(4)?),
+                        ))
+                    },
+                )
+                .optional()?;
+
+            match utxo_info {
+                None => return Err(Error::MissingUtxo(utxo_id)),
+                Some((_, true, _)) => return Err(Error::SpentUtxo(utxo_id)),
+                Some((stored_amount, false, account)) => {
+                    let key = || format!("utxos ({}, {pos})", Self::hex(&hash_id));
+                    let stored_amount = Self::blob_to_amount(&stored_amount, key)?;
+                    let account = Self::int_to_account(account, key)?;
+                    if stored_amount != *input.amount() {
+                        return Err(Error::MismatchAmount);
+                    }
+                    if account.asset() != input.asset() {
+                        return Err(Error::MismatchAsset);
+                    }
+                    accounts.insert(account);
+                    debits.push((account, stored_amount.checked_neg().ok_or(Error::Math)?));
+                }
+            }
+        }
+
+        accounts.extend(tx.outputs().iter().map(|(account, _)| *account));
+
+        let reference = tx.reference();
+
+        // Check for duplicate references
+        for account in accounts.iter() {
+            let account_id = account.id() as i64;
+            let account_type = Self::account_type_to_int(account.typ());
+
+            let ref_exists: bool = conn
+                .query_row(
+                    "SELECT 1 FROM tx_references
+                     WHERE account_id = ? AND account_type = ? AND asset = ?
+                     AND reference_kind = ? AND reference = ?",
+                    params![
+                        account_id,
+                        account_type,
+                        account.asset() as i64,
+                        reference.kind().to_byte() as i64,
+                        reference.id()
+                    ],
+                    |_| Ok(true),
+                )
+                .optional()?
+                .unwrap_or(false);
+
+            if ref_exists {
+                return Err(Error::Duplicate);
+            }
+        }
+
+        // All checks passed, store the transaction
+        let tx_data = serde_json::to_vec(&tx)?;
+        conn.execute(
+            "INSERT INTO transactions (tx_id, tx_data) VALUES (?, ?)",
+            params![tx_id_bytes, tx_data],
+        )
+        .map_err(Self::insert_error)?;
+
+        // Mark input UTXOs as spent
+        for input in tx.inputs() {
+            let utxo_id = input.id();
+            let (hash_id, pos) = (utxo_id.hash_id(), utxo_id.pos());
+
+            let updated = conn.execute(
+                "UPDATE utxos SET spent_at = ?
+                     WHERE hash_id = ? AND pos = ? AND spent_at IS NULL",
+                params![tx_id_bytes, hash_id.as_slice(), pos as i64],
+            )?;
+            if updated != 1 {
+                return Err(Error::SpentUtxo(utxo_id));
+            }
+        }
+
+        for (account, amount) in debits {
+            Self::add_to_balance(conn, &account, amount)?;
+        }
+
+        // Create new UTXOs and update references
+        for (pos, (account, amount)) in tx.outputs().iter().enumerate() {
+            let account_id = account.id() as i64;
+            let account_type = Self::account_type_to_int(account.typ());
+            let asset = account.asset() as i64;
+            let pos = pos as i64;
+
+            // Insert new UTXO
+            conn.execute(
+                "INSERT INTO utxos
+                     (hash_id, pos, account_id, account_type, asset, amount, spent_at)
+                     VALUES (?, ?, ?, ?, ?, ?, NULL)",
+                params![
+                    tx_id_bytes,
+                    pos,
+                    account_id,
+                    account_type,
+                    asset,
+                    Self::amount_to_blob(**amount)
+                ],
+            )
+            .map_err(Self::insert_error)?;
+
+            Self::add_to_balance(conn, account, **amount)?;
+
+            // Track account
+            conn.execute(
+                "INSERT OR IGNORE INTO accounts (account_id, account_type, asset)
+                     VALUES (?, ?, ?)",
+                params![account_id, account_type, asset],
+            )?;
+        }
+
+        // The reference is taken for every account involved, spenders included
+        for account in accounts {
+            conn.execute(
+                "INSERT INTO tx_references
+                     (account_id, account_type, asset, reference_kind, reference, tx_id)
+                     VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    account.id() as i64,
+                    Self::account_type_to_int(account.typ()),
+                    account.asset() as i64,
+                    reference.kind().to_byte() as i64,
+                    reference.id(),
+                    tx_id_bytes
+                ],
+            )
+            .map_err(Self::insert_error)?;
+
+            conn.execute(
+                "INSERT INTO account_txs (account_id, account_type, asset, timestamp, tx_id)
+                     VALUES (?, ?, ?, ?, ?)",
+                params![
+                    account.id() as i64,
+                    Self::account_type_to_int(account.typ()),
+                    account.asset() as i64,
+                    Self::timestamp_to_int(tx.timestamp()),
+                    tx_id_bytes
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Lists the unspent UTXOs of an account, oldest first, optionally capped to cover a target.
+    fn unspent(
+        conn: &Connection,
+        account: &FullAccount,
+        target_amount: Option<Amount>,
+    ) -> Result<Vec<Utxo>, Error> {
+        let mut stmt = conn.prepare(
+            "SELECT hash_id, pos, amount FROM utxos
+                 WHERE account_id = ? AND account_type = ? AND asset = ? AND spent_at IS NULL
+                 ORDER BY rowid",
+        )?;
+
+        let account_id = account.id() as i64;
+        let account_type = Self::account_type_to_int(account.typ());
+
+        let rows = stmt.query_map(
+            params![account_id, account_type, account.asset() as i64],
+            |row| {
+                let hash_id: Vec<u8> = row.get(0)?;
+                let pos: i64 = row.get(1)?;
+                let amount: Vec<u8> = row.get(2)?;
+                Ok((hash_id, pos, amount))
+            },
+        )?;
+
+        let mut result = Vec::new();
+        let mut total: i128 = 0;
+
+        for row in rows {
+            let (hash_id, pos, amount) = row?;
+            let utxo = Self::row_to_utxo(&hash_id, pos, &amount, account.asset())?;
+            let amount = utxo.amount();
+
+            result.push(utxo);
+
+            if let Some(target) = target_amount {
+                total = total.checked_add(*amount).ok_or(Error::Math)?;
+                if *target <= total {
+                    break;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Reads the running balance of an account.
+    fn balance(conn: &Connection, account: &FullAccount) -> Result<Amount, Error> {
+        let balance: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT balance FROM balances
+                 WHERE account_id = ? AND account_type = ? AND asset = ?",
+                params![
+                    account.id() as i64,
+                    Self::account_type_to_int(account.typ()),
+                    account.asset() as i64
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        balance
+            .map(|blob| Self::blob_to_amount(&blob, || format!("balances {account:?}")))
+            .transpose()
+            .map(|balance| Amount::from(balance.unwrap_or_default()))
+    }
+
+    /// Reads the credit limit of a Credit sub-account.
+    fn credit_limit(conn: &Connection, account: &FullAccount) -> Result<Amount, Error> {
+        let limit: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT credit_limit FROM credit_limits
+                 WHERE account_id = ? AND account_type = ? AND asset = ?",
+                params![
+                    account.id() as i64,
+                    Self::account_type_to_int(account.typ()),
+                    account.asset() as i64
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        limit
+            .map(|blob| Self::blob_to_amount(&blob, || format!("credit_limits {account:?}")))
+            .transpose()
+            .map(|limit| Amount::from(limit.unwrap_or_default()))
+    }
+
+    /// Moves the running balance of an account inside an open SQL transaction.
+    ///
+    /// Debits to a Credit sub-account fail if they take it past the account's credit limit, and
+    /// credits to a Debt or Credit sub-account fail if they take it above zero.
+    fn add_to_balance(conn: &Connection, account: &FullAccount, amount: i128) -> Result<(), Error> {
+        let balance = Self::balance(conn, account)?
+            .checked_add(amount)
+            .ok_or(Error::Math)?;
+
+        if account.typ() == AccountType::Credit
+            && amount < 0
+            && balance
+                < Self::credit_limit(conn, account)?
+                    .checked_neg()
+                    .ok_or(Error::Math)?
+        {
+            return Err(Error::CreditLimit(*account));
+        }
+
+        if matches!(account.typ(), AccountType::Debt | AccountType::Credit)
+            && amount > 0
+            && balance > 0
+        {
+            return Err(Error::Overpaid(*account));
+        }
+
+        conn.execute(
+            "INSERT INTO balances (account_id, account_type, asset, balance) VALUES (?, ?, ?, ?)
+                 ON CONFLICT (account_id, account_type, asset)
+                 DO UPDATE SET balance = excluded.balance",
+            params![
+                account.id() as i64,
+                Self::account_type_to_int(account.typ()),
+                account.asset() as i64,
+                Self::amount_to_blob(balance)
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Lists the unspent UTXOs of an account in the given order, capped to cover a target.
+    fn unspent_sorted(
+        conn: &Connection,
+        account: &FullAccount,
+        order: UtxoOrder,
+        target_amount: Option<Amount>,
+    ) -> Result<Vec<Utxo>, Error> {
+        let order_by = match order {
+            UtxoOrder::OldestFirst => "rowid",
+            UtxoOrder::NewestFirst => "rowid DESC",
+            UtxoOrder::LargestFirst => "amount DESC, rowid",
+            UtxoOrder::SmallestFirst => "amount, rowid",
+        };
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT hash_id, pos, amount FROM utxos
+                 WHERE account_id = ? AND account_type = ? AND asset = ? AND spent_at IS NULL
+                 ORDER BY {order_by}"
+        ))?;
+
+        let account_id = account.id() as i64;
+        let account_type = Self::account_type_to_int(account.typ());
+
+        let rows = stmt.query_map(
+            params![account_id, account_type, account.asset() as i64],
+            |row| {
+                let hash_id: Vec<u8> = row.get(0)?;
+                let pos: i64 = row.get(1)?;
+                let amount: Vec<u8> = row.get(2)?;
+                Ok((hash_id, pos, amount))
+            },
+        )?;
+
+        let mut result = Vec::new();
+        let mut total: i128 = 0;
+
+        for row in rows {
+            if let Some(target) = target_amount
+                && *target <= total
+            {
+                break;
+            }
+
+            let (hash_id, pos, amount) = row?;
+            let utxo = Self::row_to_utxo(&hash_id, pos, &amount, account.asset())?;
+
+            total = total.checked_add(*utxo.amount()).ok_or(Error::Math)?;
+            result.push(utxo);
+        }
+
+        Ok(result)
+    }
+
+    /// Lists the UTXOs an account held at a point in time.
+    fn unspent_at(
+        conn: &Connection,
+        account: &FullAccount,
+        timestamp: u64,
+    ) -> Result<Vec<Utxo>, Error> {
+        // The history index holds the timestamp of every transaction an account took part in,
+        // both the one creating a UTXO and the one spending it
+        let mut stmt = conn.prepare(
+            "SELECT utxos.hash_id, utxos.pos, utxos.amount FROM utxos
+                 JOIN account_txs created
+                    ON created.account_id = utxos.account_id
+                    AND created.account_type = utxos.account_type
+                    AND created.asset = utxos.asset
+                    AND created.tx_id = utxos.hash_id
+                 LEFT JOIN account_txs spent
+                    ON spent.account_id = utxos.account_id
+                    AND spent.account_type = utxos.account_type
+                    AND spent.asset = utxos.asset
+                    AND spent.tx_id = utxos.spent_at
+                 WHERE utxos.account_id = ?1 AND utxos.account_type = ?2 AND utxos.asset = ?4
+                 AND created.timestamp <= ?3
+                 AND (spent.timestamp IS NULL OR spent.timestamp > ?3)
+                 ORDER BY utxos.rowid",
+        )?;
+
+        let account_id = account.id() as i64;
+        let account_type = Self::account_type_to_int(account.typ());
+
+        let rows = stmt.query_map(
+            params![
+                account_id,
+                account_type,
+                Self::timestamp_to_int(timestamp),
+                account.asset() as i64
+            ],
+            |row| {
+                let hash_id: Vec<u8> = row.get(0)?;
+                let pos: i64 = row.get(1)?;
+                let amount: Vec<u8> = row.get(2)?;
+                Ok((hash_id, pos, amount))
+            },
+        )?;
+
+        let mut result = Vec::new();
+
+        for row in rows {
+            let (hash_id, pos, amount) = row?;
+            result.push(Self::row_to_utxo(&hash_id, pos, &amount, account.asset())?);
+        }
+
+        Ok(result)
+    }
+
+    fn set_limit(conn: &Connection, account: &FullAccount, limit: Amount) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO credit_limits (account_id, account_type, asset, credit_limit)
+                 VALUES (?, ?, ?, ?)
+                 ON CONFLICT (account_id, account_type, asset)
+                 DO UPDATE SET credit_limit = excluded.credit_limit",
+            params![
+                account.id() as i64,
+                Self::account_type_to_int(account.typ()),
+                account.asset() as i64,
+                Self::amount_to_blob(*limit)
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn tx(conn: &Connection, tx_id: &HashId) -> Result<Option<Transaction>, Error> {
+        let tx_data: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT tx_data FROM transactions WHERE tx_id = ?",
+                params![tx_id.as_slice()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        tx_data
+            .map(|tx_data| Self::data_to_tx(tx_id, &tx_data))
+            .transpose()
+    }
+
+    fn tx_by_reference(
+        conn: &Connection,
+        account: &FullAccount,
+        reference: &Reference,
+    ) -> Result<Option<Transaction>, Error> {
+        let account_id = account.id() as i64;
+        let account_type = Self::account_type_to_int(account.typ());
+
+        let tx_id: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT tx_id FROM tx_references
+                 WHERE account_id = ? AND account_type = ? AND asset = ?
+                 AND reference_kind = ? AND reference = ?",
+                params![
+                    account_id,
+                    account_type,
+                    account.asset() as i64,
+                    reference.kind().to_byte() as i64,
+                    reference.id()
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let tx_id = match tx_id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let tx_data: Vec<u8> = conn.query_row(
+            "SELECT tx_data FROM transactions WHERE tx_id = ?",
+            params![tx_id],
+            |row| row.get(0),
+        )?;
+
+        Self::data_to_tx(&tx_id, &tx_data).map(Some)
+    }
+
+    fn history(
+        conn: &Connection,
+        account: AccountId,
+        sub_account: Option<AccountType>,
+        query: &HistoryQuery,
+    ) -> Result<Vec<Transaction>, Error> {
+        // The cursor narrows the end of the range the pagination moves towards
+        let (cursor_cmp, direction) = match query.order {
+            Order::NewestFirst => ("<", "DESC"),
+            Order::OldestFirst => (">", "ASC"),
+        };
+
+        let sql = format!(
+            "SELECT DISTINCT account_txs.timestamp, account_txs.tx_id, transactions.tx_data
+             FROM account_txs
+             JOIN transactions ON transactions.tx_id = account_txs.tx_id
+             WHERE account_txs.account_id = ?1
+             AND (?2 IS NULL OR account_txs.account_type = ?2)
+             AND account_txs.timestamp >= ?3
+             AND (?4 IS NULL OR account_txs.timestamp < ?4)
+             AND (?5 IS NULL OR account_txs.timestamp {cursor_cmp} ?5
+                OR (account_txs.timestamp = ?5 AND account_txs.tx_id {cursor_cmp} ?6))
+             ORDER BY account_txs.timestamp {direction}, account_txs.tx_id {direction}
+             LIMIT ?7"
+        );
+
+        let mut stmt = conn.prepare(&sql)?;
+
+        let rows = stmt.query_map(
+            params![
+                account as i64,
+                sub_account.map(Self::account_type_to_int),
+                Self::timestamp_to_int(query.since.unwrap_or(0)),
+                query.until.map(Self::timestamp_to_int),
+                query
+                    .after
+                    .map(|cursor| Self::timestamp_to_int(cursor.timestamp())),
+                query.after.map(|cursor| cursor.tx_id().to_vec()),
+                query.limit.min(i64::MAX as usize) as i64,
+            ],
+            |row| Ok((row.get::<_, Vec<u8>>(1)?, row.get::<_, Vec<u8>>(2)?)),
+        )?;
+
+        rows.map(|row| {
+            let (tx_id, tx_data) = row?;
+            Self::data_to_tx(&tx_id, &tx_data)
+        })
+        .collect()
+    }
+
+    fn store(conn: &mut Connection, txs: &[Transaction]) -> Result<(), Error> {
+        // The whole batch shares a single SQL transaction, dropping it on error rolls back the
+        // transactions stored so far. It takes the write lock before validating, so no other
+        // connection, in this process or another, can spend the inputs in between.
+        let sql_tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        for tx in txs {
+            Self::store_one(&sql_tx, tx)?;
+        }
+
+        sql_tx.commit()?;
+
+        Ok(())
+    }
+}
+
+/// A read transaction on a connection of its own, rolled back once the reads are done.
+///
+/// The reads run in the blocking task that opened the transaction. In WAL mode they never wait
+/// on a lock, the snapshot is the only user of its connection.
+pub struct SqliteSnapshot<'conn> {
+    conn: rusqlite::Transaction<'conn>,
+}
+
+impl Snapshot for SqliteSnapshot<'_> {
+    fn get_balance(&self, account: &FullAccount) -> Result<Amount, Error> {
+        Sqlite::balance(&self.conn, account)
+    }
+
+    fn get_unspent(&self, account: &FullAccount) -> Result<Vec<Utxo>, Error> {
+        Sqlite::unspent(&self.conn, account, None)
+    }
+
+    fn get_credit_limit(&self, account: &FullAccount) -> Result<Amount, Error> {
+        Sqlite::credit_limit(&self.conn, account)
+    }
+
+    fn get_assets(&self, account: AccountId) -> Result<BTreeSet<AssetId>, Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT asset FROM account_txs WHERE account_id = ?")?;
+
+        let rows = stmt.query_map(params![account as i64], |row| row.get::<_, i64>(0))?;
+
+        rows.map(|asset| {
+            AssetId::try_from(asset?)
+                .map_err(|err| Sqlite::corrupt(format!("account_txs ({account})"), err))
+        })
+        .collect()
+    }
+}
+
+/// Number of accounts `AccountStream` reads at a time.
+const ACCOUNTS_PAGE: usize = 100;
+
+/// Key of a row of the accounts table.
+type AccountKey = (i64, i64, i64);
+
+/// Stream for iterating over accounts in sorted order.
+///
+/// Accounts are read a page at a time by a task of their own, polling the stream never blocks.
+/// Each page starts after the last account of the previous one, so accounts created while
+/// streaming neither shift nor repeat the ones already read.
+pub struct AccountStream {
+    readers: Arc<Pool>,
+    after: Option<AccountKey>,
+    page: VecDeque<AccountKey>,
+    exhausted: bool,
+    next: Option<JoinHandle<Result<Vec<AccountKey>, Error>>>,
+}
+
+impl Stream for AccountStream {
+    type Item = Result<FullAccount, Error>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(row) = this.page.pop_front() {
+                // A corrupt row is reported once, the stream carries on past it
+                let (account_id, account_type, asset) = row;
+                return Poll::Ready(Some(Sqlite::int_to_account(row, || {
+                    format!("accounts ({account_id}, {account_type}, {asset})")
+                })));
+            }
+
+            if this.exhausted {
+                return Poll::Ready(None);
+            }
+
+            let after = this.after;
+            let readers = this.readers.clone();
+            let next = this.next.get_or_insert_with(|| {
+                tokio::spawn(async move {
+                    Sqlite::run(&readers, move |conn| {
+                        let mut stmt = conn.prepare(
+                            "SELECT account_id, account_type, asset FROM accounts
+                             WHERE ?1 IS NULL OR (account_id, account_type, asset) > (?1, ?2, ?3)
+                             ORDER BY account_id, account_type, asset
+                             LIMIT ?4",
+                        )?;
+                        let rows = stmt.query_map(
+                            params![
+                                after.map(|key| key.0),
+                                after.map(|key| key.1),
+                                after.map(|key| key.2),
+                                ACCOUNTS_PAGE as i64
+                            ],
+                            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                        )?;
+                        Ok(rows.collect::<Result<_, _>>()?)
+                    })
+                    .await
+                })
+            });
+
+            let result = match Pin::new(next).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => result
+                    .map_err(|err| Error::Backend(Box::new(err)))
+                    .and_then(|r| r),
+            };
+            this.next = None;
+
+            match result {
+                Ok(rows) => {
+                    // A short page means there is nothing after it
+                    this.exhausted = rows.len() < ACCOUNTS_PAGE;
+                    this.after = rows.last().copied().or(this.after);
+                    this.page.extend(rows);
+                }
+                Err(err) => {
+                    // A page that cannot be read ends the stream
+                    this.exhausted = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        match err.sqlite_error_code() {
+            Some(ErrorCode::ConstraintViolation) => Error::Constraint(Box::new(err)),
+            _ => Error::Backend(Box::new(err)),
+        }
+    }
+}
+
+trait OptionalExt<T> {
+    fn optional(self) -> Result<Option<T>, rusqlite::Error>;
+}
+
+impl<T> OptionalExt<T> for Result<T, rusqlite::Error> {
+    fn optional(self) -> Result<Option<T>, rusqlite::Error> {
+        match self {
+            Ok(val) => Ok(Some(val)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for Sqlite {
+    async fn get_accounts(&self) -> AccountStream {
+        AccountStream {
+            readers: self.readers.clone(),
+            after: None,
+            page: VecDeque::new(),
+            exhausted: false,
+            next: None,
+        }
+    }
+
+    async fn get_unspent(
+        &self,
+        account: &FullAccount,
+        target_amount: Option<Amount>,
+    ) -> Result<Vec<Utxo>, Error> {
+        let account = *account;
+        self.read(move |conn| Self::unspent(conn, &account, target_amount))
+            .await
+    }
+
+    async fn get_unspent_sorted(
+        &self,
+        account: &FullAccount,
+        order: UtxoOrder,
+        target_amount: Option<Amount>,
+    ) -> Result<Vec<Utxo>, Error> {
+        let account = *account;
+        self.read(move |conn| Self::unspent_sorted(conn, &account, order, target_amount))
+            .await
+    }
+
+    async fn get_unspent_at(
+        &self,
+        account: &FullAccount,
+        timestamp: u64,
+    ) -> Result<Vec<Utxo>, Error> {
+        let account = *account;
+        self.read(move |conn| Self::unspent_at(conn, &account, timestamp))
+            .await
+    }
+
+    async fn get_balance(&self, account: &FullAccount) -> Result<Amount, Error> {
+        let account = *account;
+        self.read(move |conn| Self::balance(conn, &account)).await
+    }
+
+    async fn get_credit_limit(&self, account: &FullAccount) -> Result<Amount, Error> {
+        let account = *account;
+        self.read(move |conn| Self::credit_limit(conn, &account))
+            .await
+    }
+
+    async fn set_credit_limit(&self, account: &FullAccount, limit: Amount) -> Result<(), Error> {
+        let account = *account;
+        self.write(move |conn| Self::set_limit(conn, &account, limit))
+            .await
+    }
+
+    async fn snapshot<T, F>(&self, reads: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Snapshot) -> Result<T, Error> + Send + 'static,
+    {
+        self.read(move |conn| {
+            reads(&SqliteSnapshot {
+                conn: conn.transaction()?,
+            })
+        })
+        .await
+    }
+
+    async fn get_tx(&self, tx_id: &HashId) -> Result<Option<Transaction>, Error> {
+        let tx_id = *tx_id;
+        self.read(move |conn| Self::tx(conn, &tx_id)).await
+    }
+
+    async fn get_tx_by_reference(
+        &self,
+        account: &FullAccount,
+        reference: &Reference,
+    ) -> Result<Option<Transaction>, Error> {
+        let account = *account;
+        let reference = reference.clone();
+        self.read(move |conn| Self::tx_by_reference(conn, &account, &reference))
+            .await
+    }
+
+    async fn get_history(
+        &self,
+        account: AccountId,
+        sub_account: Option<AccountType>,
+        query: &HistoryQuery,
+    ) -> Result<Vec<Transaction>, Error> {
+        let query = *query;
+        self.read(move |conn| Self::history(conn, account, sub_account, &query))
+            .await
+    }
+
+    async fn store_tx(&self, tx: Transaction) -> Result<(), Error> {
+        self.store_txs(vec![tx]).await
+    }
+
+    async fn store_txs(&self, txs: Vec<Transaction>) -> Result<(), Error> {
+        self.write(move |conn| Self::store(conn, &txs)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::storage_test!(Sqlite::default());
+
+    /// A database file removed when dropped.
+    pub(super) struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        pub(super) fn new(name: &str) -> Self {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("time went backwards")
+                .as_nanos();
+            TempFile(
+                std::env::temp_dir()
+                    .join(format!("ledger-{name}-{}-{nanos}.db", std::process::id())),
+            )
+        }
+
+        pub(super) fn path(&self) -> &str {
+            self.0.to_str().expect("temp path should be valid UTF-8")
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            for suffix in ["-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_double_spend_across_connections() {
+        const CONNECTIONS: u16 = 8;
+        let file = TempFile::new("double-spend");
+
+        // Separate connections to the same file, as separate processes would have
+        let storages = (0..CONNECTIONS)
+            .map(|_| Arc::new(Sqlite::open(file.path(), 1).expect("open should succeed")))
+            .collect::<Vec<_>>();
+
+        let deposit = Transaction::new(
+            vec![],
+            vec![(1.into(), 100.into())],
+            "deposit-1".into(),
+            Some(1000),
+        )
+        .expect("deposit should be valid");
+        let utxo = Utxo::new((deposit.id(), 0).into(), 100.into(), crate::DEFAULT_ASSET);
+        storages[0]
+            .store_tx(deposit)
+            .await
+            .expect("deposit should succeed");
+
+        let barrier = Arc::new(tokio::sync::Barrier::new(CONNECTIONS.into()));
+        let tasks = storages
+            .into_iter()
+            .zip(2..)
+            .map(|(storage, to)| {
+                let barrier = barrier.clone();
+                tokio::spawn(async move {
+                    let spend = Transaction::new(
+                        vec![utxo],
+                        vec![(to.into(), 100.into())],
+                        "spend-1".into(),
+                        Some(2000),
+                    )
+                    .expect("spend should be valid");
+                    barrier.wait().await;
+                    storage.store_tx(spend).await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut committed = 0;
+        for task in tasks {
+            match task.await.expect("task should not panic") {
+                Ok(()) => committed += 1,
+                Err(Error::SpentUtxo(id)) => assert_eq!(id, utxo.id()),
+                Err(err) => panic!("unexpected error {err:?}"),
+            }
+        }
+        assert_eq!(committed, 1);
+
+        let storage = Sqlite::open(file.path(), 1).expect("open should succeed");
+        let mut owners = 0;
+        for to in 2..2 + CONNECTIONS {
+            let balance = storage
+                .get_balance(&to.into())
+                .await
+                .expect("get_balance should succeed");
+            if *balance == 100 {
+                owners += 1;
+            }
+        }
+        assert_eq!(owners, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_snapshot_does_not_hold_back_writer() {
+        let file = TempFile::new("wal-snapshot");
+        let storage = Arc::new(Sqlite::open(file.path(), 2).expect("open should succeed"));
+        let account: FullAccount = 1.into();
+
+        let deposit = move |reference: &str| {
+            Transaction::new(
+                vec![],
+                vec![(account, 100.into())],
+                reference.into(),
+                Some(1000),
+            )
+            .expect("deposit should be valid")
+        };
+
+        storage
+            .store_tx(deposit("deposit-1"))
+            .await
+            .expect("deposit should succeed");
+
+        let writer = storage.clone();
+        let (before, after) = storage
+            .snapshot(move |snapshot| {
+                let before = snapshot.get_balance(&account)?;
+
+                // The writer commits while the snapshot is still open
+                tokio::runtime::Handle::current()
+                    .block_on(tokio::time::timeout(
+                        Duration::from_secs(1),
+                        writer.store_tx(deposit("deposit-2")),
+                    ))
+                    .expect("the writer should not wait for the snapshot")?;
+
+                Ok((before, snapshot.get_balance(&account)?))
+            })
+            .await
+            .expect("snapshot should succeed");
+
+        assert_eq!((*before, *after), (100, 100));
+        assert_eq!(
+            *storage
+                .get_balance(&account)
+                .await
+                .expect("get_balance should succeed"),
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accounts_are_streamed_in_pages() {
+        use futures::StreamExt;
+
+        let storage = Sqlite::default();
+        let total = ACCOUNTS_PAGE * 2 + 10;
+        let insert = |sql: &str| {
+            let sql = sql.to_owned();
+            let writer = storage.writer.clone();
+            async move {
+                writer
+                    .get()
+                    .await
+                    .expect("the writer should be free")
+                    .execute_batch(&sql)
+                    .expect("insert should succeed")
+            }
+        };
+        insert(&format!(
+            "WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < {total})
+             INSERT INTO accounts (account_id, account_type, asset) SELECT id, 0, 0 FROM ids"
+        ))
+        .await;
+
+        let mut accounts = storage.get_accounts().await;
+        let first = accounts
+            .next()
+            .await
+            .expect("an account should be listed")
+            .expect("the account should be valid");
+        assert_eq!(first, 1.into());
+
+        // An account before the read position is not picked up, nor shifts the next pages
+        insert("INSERT INTO accounts (account_id, account_type, asset) VALUES (1, 1, 0)").await;
+
+        let rest = accounts
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("every account should be valid");
+        let expected = (2..=total)
+            .map(|id| FullAccount::from(id as AccountId))
+            .collect::<Vec<_>>();
+        assert_eq!(rest, expected);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_rows_are_reported() {
+        use futures::StreamExt;
+
+        let storage = Sqlite::default();
+        let account: FullAccount = 1.into();
+        let deposit = Transaction::new(
+            vec![],
+            vec![(account, 100.into())],
+            "deposit-1".into(),
+            Some(1000),
+        )
+        .expect("deposit should be valid");
+        let tx_id = deposit.id();
+        storage
+            .store_tx(deposit)
+            .await
+            .expect("deposit should succeed");
+
+        storage
+            .writer
+            .get()
+            .await
+            .expect("the writer should be free")
+            .execute_batch(
+                "UPDATE accounts SET account_type = 42;
+                 UPDATE balances SET balance = x'00';
+                 UPDATE transactions SET tx_data = x'00';",
+            )
+            .expect("corrupting the rows should succeed");
+
+        // An unknown account type is not read as Main
+        let mut accounts = storage.get_accounts().await;
+        assert!(matches!(
+            accounts.next().await,
+            Some(Err(Error::Corrupt { key, .. })) if key == "accounts (1, 42, 0)"
+        ));
+        assert!(accounts.next().await.is_none());
+
+        assert!(matches!(
+            storage.get_balance(&account).await,
+            Err(Error::Corrupt { key, .. }) if key.starts_with("balances")
+        ));
+        assert!(matches!(
+            storage.get_tx(&tx_id).await,
+            Err(Error::Corrupt { key, source })
+                if key == format!("transactions ({})", Sqlite::hex(&tx_id))
+                    && source.is::<serde_json::Error>()
+        ));
+    }
+}
@@ -0,0 +1,104 @@
+//! A fixed set of SQLite connections, each handed to one task at a time.
+use parking_lot::Mutex;
+use rusqlite::Connection;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::storage::Error;
+
+/// Connections shared between tasks, a task waits asynchronously until one is free.
+pub(super) struct Pool {
+    idle: Mutex<Vec<Connection>>,
+    available: Semaphore,
+}
+
+impl Pool {
+    pub(super) fn new(conns: Vec<Connection>) -> Arc<Self> {
+        Arc::new(Self {
+            available: Semaphore::new(conns.len()),
+            idle: Mutex::new(conns),
+        })
+    }
+
+    /// Takes a free connection, waiting for one to be returned if all of them are in use.
+    pub(super) async fn get(self: &Arc<Self>) -> Result<Pooled, Error> {
+        // The permit is given back with the connection, see `Pooled::drop`
+        self.available
+            .acquire()
+            .await
+            .map_err(|_| Error::Internal)?
+            .forget();
+
+        let conn = self
+            .idle
+            .lock()
+            .pop()
+            .expect("a permit is only available with an idle connection");
+
+        Ok(Pooled {
+            pool: self.clone(),
+            conn: Some(conn),
+        })
+    }
+}
+
+/// A connection taken from a pool, returned to it when dropped.
+pub(super) struct Pooled {
+    pool: Arc<Pool>,
+    conn: Option<Connection>,
+}
+
+impl Deref for Pooled {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("the connection is only taken on drop")
+    }
+}
+
+impl DerefMut for Pooled {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn
+            .as_mut()
+            .expect("the connection is only taken on drop")
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().push(conn);
+            self.pool.available.add_permits(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn connections_are_handed_out_one_at_a_time() {
+        let pool = Pool::new(vec![
+            Connection::open_in_memory().expect("open should succeed"),
+        ]);
+
+        let first = pool.get().await.expect("a connection should be free");
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get().await.map(|_| ()) }
+        });
+
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting
+            .await
+            .expect("task should not panic")
+            .expect("the returned connection should be handed out");
+    }
+}
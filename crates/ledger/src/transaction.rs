@@ -1,12 +1,17 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-use crate::{Amount, FullAccount, Reference};
+use crate::{Amount, AssetId, FullAccount, Rate, Reference, asset};
 
+/// SHA256 hash identifying a transaction.
 pub type HashId = [u8; 32];
 
+/// Identifies a UTXO by the transaction that created it and its position in the outputs.
 #[derive(Debug, Hash, Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
 pub struct UtxoId {
     id: HashId,
@@ -21,6 +26,8 @@ pub enum Error {
     InvalidTo,
     #[error("Imbalanced transaction")]
     Imbalanced,
+    #[error("Overflow adding up the amounts of a Tx")]
+    Math,
 }
 
 /// Unspent transaction Output
@@ -37,6 +44,13 @@ pub enum Error {
 pub struct Utxo {
     id: UtxoId,
     amount: Amount,
+    #[serde(default = "default_asset")]
+    asset: AssetId,
+}
+
+/// UTXOs stored before assets existed belong to the default asset.
+fn default_asset() -> AssetId {
+    asset::DEFAULT
 }
 
 impl From<(HashId, u8)> for UtxoId {
@@ -49,18 +63,21 @@ impl From<(HashId, u8)> for UtxoId {
 }
 
 impl UtxoId {
+    /// Returns the ID of the transaction that created the UTXO.
     pub fn hash_id(&self) -> HashId {
         self.id
     }
 
+    /// Returns the position of the UTXO among the transaction outputs.
     pub fn pos(&self) -> u8 {
         self.pos
     }
 }
 
 impl Utxo {
-    pub fn new(id: UtxoId, amount: Amount) -> Self {
-        Self { id, amount }
+    /// Creates a reference to an existing UTXO, used as a transaction input.
+    pub fn new(id: UtxoId, amount: Amount, asset: AssetId) -> Self {
+        Self { id, amount, asset }
     }
 
     fn into_to_bytes(self) -> [u8; 33] {
@@ -70,13 +87,20 @@ impl Utxo {
         bytes
     }
 
+    /// Returns where the UTXO was created.
     pub fn id(&self) -> UtxoId {
         self.id
     }
 
+    /// Returns the value of the UTXO.
     pub fn amount(&self) -> Amount {
         self.amount
     }
+
+    /// Returns the asset the value is denominated in.
+    pub fn asset(&self) -> AssetId {
+        self.asset
+    }
 }
 
 /// Simplified version of an transaction, lot of details are left out due to time constraints
@@ -90,9 +114,29 @@ pub struct Transaction {
     to: Vec<(FullAccount, Amount)>,
     reference: Reference,
     timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rate: Option<Rate>,
+}
+
+/// Returns the current system time in microseconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_micros() as u64
 }
 
 impl Transaction {
+    /// Creates a new transaction spending `from` and creating the `to` outputs.
+    ///
+    /// Either side may be empty but not both. Deposits have no inputs, their outputs credit the
+    /// account and debit its External sub-account by the same amount; withdrawals spend into the
+    /// External sub-account, only those stored before it existed have no outputs. When both
+    /// sides are present they must balance for every asset on its own, and the
+    /// inputs of each asset must add up to a positive amount. A `None` timestamp defaults to
+    /// the current system time in microseconds.
     pub fn new(
         from: Vec<Utxo>,
         to: Vec<(FullAccount, Amount)>,
@@ -104,45 +148,87 @@ impl Transaction {
         }
 
         if !from.is_empty() && !to.is_empty() {
-            let spending: i128 = from.iter().map(|input| *input.amount).sum();
-            let receiving: i128 = to.iter().map(|(_, amount)| **amount).sum();
-
-            if spending != receiving {
-                return Err(Error::Imbalanced);
+            // (spending, receiving) per asset
+            let mut sums: BTreeMap<AssetId, (i128, i128)> = BTreeMap::new();
+            for input in &from {
+                let sum = &mut sums.entry(input.asset).or_default().0;
+                *sum = sum.checked_add(*input.amount).ok_or(Error::Math)?;
+            }
+            for (account, amount) in &to {
+                let sum = &mut sums.entry(account.asset()).or_default().1;
+                *sum = sum.checked_add(**amount).ok_or(Error::Math)?;
             }
 
-            if spending <= 0 {
-                return Err(Error::InvalidFrom);
+            for (asset, (spending, receiving)) in sums {
+                if spending != receiving {
+                    return Err(Error::Imbalanced);
+                }
+
+                if spending <= 0 && from.iter().any(|input| input.asset == asset) {
+                    return Err(Error::InvalidFrom);
+                }
             }
         }
 
-        let timestamp = timestamp.unwrap_or_else(|| {
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_micros() as u64
-        });
+        let timestamp = timestamp.unwrap_or_else(now);
 
         Ok(Self {
             from,
             to,
             timestamp,
             reference,
+            expires_at: None,
+            rate: None,
         })
     }
 
+    /// Sets the instant, in microseconds since the Unix epoch, after which the transaction is
+    /// no longer honoured. Used by authorisation holds, the expiry is part of the ID.
+    pub fn set_expires_at(&mut self, expires_at: Option<u64>) {
+        self.expires_at = expires_at;
+    }
+
+    /// Returns when the transaction expires, if it does.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Records the rate a currency exchange was made at, the rate is part of the ID.
+    pub fn set_rate(&mut self, rate: Option<Rate>) {
+        self.rate = rate;
+    }
+
+    /// Returns the exchange rate, if the transaction is a currency exchange.
+    pub fn rate(&self) -> Option<Rate> {
+        self.rate
+    }
+
+    /// Returns the UTXOs spent by this transaction.
     pub fn inputs(&self) -> &[Utxo] {
         &self.from
     }
 
+    /// Returns the outputs created by this transaction, in position order.
     pub fn outputs(&self) -> &[(FullAccount, Amount)] {
         &self.to
     }
 
+    /// Returns the reference this transaction was stored under.
     pub fn reference(&self) -> Reference {
         self.reference.clone()
     }
 
+    /// Returns the creation time in microseconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Computes the transaction ID.
+    ///
+    /// SHA256(SHA256(inputs) + SHA256(outputs) + timestamp + reference [+ expires_at] [+ rate])
+    ///
+    /// The expiry and the rate are only hashed when set, so transactions without them keep
+    /// their ID.
     pub fn id(&self) -> HashId {
         // SHA256(inputs)
         let mut inputs_hasher = Sha256::new();
@@ -164,7 +250,13 @@ impl Transaction {
         final_hasher.update(inputs_hash);
         final_hasher.update(outputs_hash);
         final_hasher.update(self.timestamp.to_le_bytes());
-        final_hasher.update(self.reference.as_bytes());
+        final_hasher.update(self.reference.to_bytes());
+        if let Some(expires_at) = self.expires_at {
+            final_hasher.update(expires_at.to_le_bytes());
+        }
+        if let Some(rate) = self.rate {
+            final_hasher.update(rate.to_bytes());
+        }
         final_hasher.finalize().into()
     }
 }
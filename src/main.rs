@@ -3,7 +3,7 @@ use std::error::Error;
 
 use csv::Trim;
 use futures::StreamExt;
-use ledger::{AccountId, Amount, Ledger};
+use ledger::{AccountId, Amount, DEFAULT_ASSET, Ledger};
 use serde::{Deserialize, Serialize};
 
 pub const AMOUNT_PRECISION: u8 = 4;
@@ -125,8 +125,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
             }
         };
 
+        // The CSV format only knows about the default asset
         let balance = match ledger.get_balances(account).await {
-            Ok(balance) => balance,
+            Ok(mut balances) => balances
+                .remove(&DEFAULT_ASSET)
+                .expect("the ledger's own asset is always listed"),
             Err(err) => {
                 eprintln!(
                     "Error reading balance for customer {} with err {:?}",
@@ -147,7 +150,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .available
                 .to_f64(AMOUNT_PRECISION)
                 .expect("valid f64"),
-            locked: (*balance.chargeback) > 0,
+            locked: balance.locked,
         };
 
         if let Err(err) = wtr.serialize(record) {